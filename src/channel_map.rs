@@ -0,0 +1,360 @@
+use crate::output_stream::BufferSample;
+use crate::scream::{
+    default_channel_mask, speaker_positions_from_mask, SpeakerPosition, MAX_CHANNELS,
+};
+use std::str::FromStr;
+
+const MINUS_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Routing matrix from source channels to output device channels. Each row
+/// holds the gains of all source channels for one output channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelMap {
+    rows: Vec<BufferSample>,
+}
+
+impl ChannelMap {
+    /// Builds a map between two speaker layouts. Channels missing from the
+    /// output are folded down using ITU-R BS.775 coefficients; with `upmix`
+    /// set, output speakers that would stay silent are fed from the source.
+    /// An output without any speaker positions, e.g. a device with more
+    /// channels than there is a default layout for, is mapped by index.
+    pub fn for_layouts(
+        input: &[SpeakerPosition],
+        output: &[SpeakerPosition],
+        upmix: bool,
+    ) -> ChannelMap {
+        let mut rows = vec![[0.0f32; MAX_CHANNELS]; output.len().min(MAX_CHANNELS)];
+        let output_has_positions = output.iter().any(|p| *p != SpeakerPosition::Unassigned);
+
+        for (input_channel, position) in input.iter().enumerate().take(MAX_CHANNELS) {
+            let targets = match position {
+                // Channels without a position go to the output channel with
+                // the same index, if there is one.
+                SpeakerPosition::Unassigned => vec![(input_channel, 1.0)],
+                _ if !output_has_positions => vec![(input_channel, 1.0)],
+                _ => route(*position, output, &mut Vec::new()),
+            };
+
+            for (output_channel, gain) in targets {
+                if let Some(row) = rows.get_mut(output_channel) {
+                    row[input_channel] += gain;
+                }
+            }
+        }
+
+        if upmix {
+            for (output_channel, position) in output.iter().enumerate().take(rows.len()) {
+                if rows[output_channel].iter().any(|gain| *gain != 0.0) {
+                    continue;
+                }
+
+                for (source, gain) in upmix_sources(*position) {
+                    if let Some(input_channel) = input.iter().position(|p| p == source) {
+                        rows[output_channel][input_channel] += gain;
+                    }
+                }
+            }
+        }
+
+        ChannelMap { rows }
+    }
+
+    /// Builds a map for a device with `output_channels` channels, assuming the
+    /// device uses the default Windows speaker layout for that channel count.
+    pub fn for_device(input: &[SpeakerPosition], output_channels: u16, upmix: bool) -> ChannelMap {
+        let output =
+            speaker_positions_from_mask(output_channels, default_channel_mask(output_channels));
+        ChannelMap::for_layouts(input, &output, upmix)
+    }
+
+    pub fn output_channels(&self) -> u16 {
+        self.rows.len() as u16
+    }
+
+    pub fn apply(&self, input: &BufferSample) -> BufferSample {
+        let mut output = [0.0f32; MAX_CHANNELS];
+
+        for (output_sample, row) in output.iter_mut().zip(self.rows.iter()) {
+            *output_sample = row.iter().zip(input.iter()).map(|(g, s)| g * s).sum();
+        }
+
+        output
+    }
+}
+
+/// Parses a routing matrix given as rows of comma separated gains, one row
+/// per output channel, separated by semicolons. For example `1,0;0,1`.
+impl FromStr for ChannelMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rows = s
+            .split(';')
+            .map(|row| {
+                let gains = row
+                    .split(',')
+                    .map(|gain| gain.trim().parse::<f32>())
+                    .collect::<Result<Vec<f32>, _>>()
+                    .map_err(|e| format!("invalid gain in channel map row '{}': {}", row, e))?;
+
+                if gains.len() > MAX_CHANNELS {
+                    return Err(format!("channel map row '{}' has too many columns", row));
+                }
+
+                let mut row = [0.0f32; MAX_CHANNELS];
+                row[..gains.len()].copy_from_slice(&gains);
+                Ok(row)
+            })
+            .collect::<Result<Vec<BufferSample>, String>>()?;
+
+        if rows.len() > MAX_CHANNELS {
+            return Err(format!(
+                "channel map has {} rows, at most {} output channels are supported",
+                rows.len(),
+                MAX_CHANNELS
+            ));
+        }
+
+        Ok(ChannelMap { rows })
+    }
+}
+
+/// Resolves a source speaker into output channels and gains. If the output
+/// lacks the speaker, the first usable substitute in `downmix_alternatives`
+/// is used instead, recursively.
+fn route(
+    position: SpeakerPosition,
+    output: &[SpeakerPosition],
+    visited: &mut Vec<SpeakerPosition>,
+) -> Vec<(usize, f32)> {
+    if let Some(channel) = output.iter().position(|p| *p == position) {
+        return vec![(channel, 1.0)];
+    }
+
+    visited.push(position);
+
+    for alternative in downmix_alternatives(position) {
+        if alternative.iter().any(|(p, _)| visited.contains(p)) {
+            continue;
+        }
+
+        let mut targets = Vec::new();
+        for (substitute, gain) in alternative.iter() {
+            let routed = route(*substitute, output, visited);
+            targets.extend(routed.into_iter().map(|(c, g)| (c, g * gain)));
+        }
+
+        if !targets.is_empty() {
+            visited.pop();
+            return targets;
+        }
+    }
+
+    visited.pop();
+    Vec::new()
+}
+
+fn downmix_alternatives(position: SpeakerPosition) -> &'static [&'static [(SpeakerPosition, f32)]] {
+    use SpeakerPosition::*;
+
+    match position {
+        FrontLeft => &[&[(FrontCenter, MINUS_3DB)]],
+        FrontRight => &[&[(FrontCenter, MINUS_3DB)]],
+        FrontCenter => &[&[(FrontLeft, MINUS_3DB), (FrontRight, MINUS_3DB)]],
+        BackLeft => &[&[(SideLeft, 1.0)], &[(FrontLeft, MINUS_3DB)]],
+        BackRight => &[&[(SideRight, 1.0)], &[(FrontRight, MINUS_3DB)]],
+        SideLeft => &[&[(BackLeft, 1.0)], &[(FrontLeft, MINUS_3DB)]],
+        SideRight => &[&[(BackRight, 1.0)], &[(FrontRight, MINUS_3DB)]],
+        BackCenter => &[
+            &[(BackLeft, MINUS_3DB), (BackRight, MINUS_3DB)],
+            &[(SideLeft, MINUS_3DB), (SideRight, MINUS_3DB)],
+            &[(FrontLeft, 0.5), (FrontRight, 0.5)],
+        ],
+        FrontLeftOfCenter => &[&[(FrontLeft, 1.0)]],
+        FrontRightOfCenter => &[&[(FrontRight, 1.0)]],
+        TopCenter => &[&[(FrontCenter, 1.0)]],
+        TopFrontLeft => &[&[(FrontLeft, 1.0)]],
+        TopFrontCenter => &[&[(FrontCenter, 1.0)]],
+        TopFrontRight => &[&[(FrontRight, 1.0)]],
+        TopBackLeft => &[&[(BackLeft, 1.0)]],
+        // ITU downmixes drop the LFE channel.
+        LowFrequency | Unassigned => &[],
+    }
+}
+
+fn upmix_sources(position: SpeakerPosition) -> &'static [(SpeakerPosition, f32)] {
+    use SpeakerPosition::*;
+
+    match position {
+        FrontCenter => &[(FrontLeft, MINUS_3DB), (FrontRight, MINUS_3DB)],
+        BackLeft | SideLeft => &[(FrontLeft, 1.0)],
+        BackRight | SideRight => &[(FrontRight, 1.0)],
+        BackCenter => &[(FrontLeft, MINUS_3DB), (FrontRight, MINUS_3DB)],
+        _ => &[],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SpeakerPosition::*;
+
+    fn layout(channels: u16) -> Vec<SpeakerPosition> {
+        speaker_positions_from_mask(channels, default_channel_mask(channels))
+    }
+
+    fn assert_row(map: &ChannelMap, output_channel: usize, expected: &[f32]) {
+        let row = &map.rows[output_channel];
+        for (input_channel, gain) in row.iter().enumerate() {
+            let expected = expected.get(input_channel).copied().unwrap_or(0.0);
+            assert!(
+                (gain - expected).abs() < 1e-6,
+                "output {} input {}: {} != {}",
+                output_channel,
+                input_channel,
+                gain,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn same_layout_is_identity() {
+        let map = ChannelMap::for_layouts(&layout(6), &layout(6), false);
+
+        assert_eq!(map.output_channels(), 6);
+        for channel in 0..6 {
+            let mut expected = [0.0; 6];
+            expected[channel] = 1.0;
+            assert_row(&map, channel, &expected);
+        }
+    }
+
+    #[test]
+    fn downmixes_5_1_to_stereo() {
+        // FL FR FC LFE BL BR
+        let map = ChannelMap::for_layouts(&layout(6), &layout(2), false);
+
+        assert_eq!(map.output_channels(), 2);
+        assert_row(&map, 0, &[1.0, 0.0, MINUS_3DB, 0.0, MINUS_3DB, 0.0]);
+        assert_row(&map, 1, &[0.0, 1.0, MINUS_3DB, 0.0, 0.0, MINUS_3DB]);
+    }
+
+    #[test]
+    fn downmixes_7_1_to_stereo() {
+        // FL FR FC LFE BL BR SL SR
+        let map = ChannelMap::for_layouts(&layout(8), &layout(2), false);
+
+        let left = [1.0, 0.0, MINUS_3DB, 0.0, MINUS_3DB, 0.0, MINUS_3DB, 0.0];
+        let right = [0.0, 1.0, MINUS_3DB, 0.0, 0.0, MINUS_3DB, 0.0, MINUS_3DB];
+        assert_row(&map, 0, &left);
+        assert_row(&map, 1, &right);
+    }
+
+    #[test]
+    fn downmixes_7_1_to_5_1_by_moving_sides_to_backs() {
+        let map = ChannelMap::for_layouts(&layout(8), &layout(6), false);
+
+        assert_row(&map, 4, &[0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 1.0, 0.0]);
+        assert_row(&map, 5, &[0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn spreads_mono_over_stereo() {
+        let map = ChannelMap::for_layouts(&[FrontCenter], &layout(2), false);
+
+        assert_row(&map, 0, &[MINUS_3DB]);
+        assert_row(&map, 1, &[MINUS_3DB]);
+    }
+
+    #[test]
+    fn folds_stereo_into_mono() {
+        let map = ChannelMap::for_layouts(&layout(2), &[FrontCenter], false);
+
+        assert_eq!(map.output_channels(), 1);
+        assert_row(&map, 0, &[MINUS_3DB, MINUS_3DB]);
+    }
+
+    #[test]
+    fn leaves_extra_speakers_silent_without_upmix() {
+        let map = ChannelMap::for_layouts(&layout(2), &layout(6), false);
+
+        assert_row(&map, 0, &[1.0, 0.0]);
+        assert_row(&map, 1, &[0.0, 1.0]);
+        for channel in 2..6 {
+            assert_row(&map, channel, &[]);
+        }
+    }
+
+    #[test]
+    fn upmixes_stereo_to_5_1() {
+        let map = ChannelMap::for_layouts(&layout(2), &layout(6), true);
+
+        assert_row(&map, 0, &[1.0, 0.0]);
+        assert_row(&map, 1, &[0.0, 1.0]);
+        assert_row(&map, 2, &[MINUS_3DB, MINUS_3DB]);
+        assert_row(&map, 3, &[]);
+        assert_row(&map, 4, &[1.0, 0.0]);
+        assert_row(&map, 5, &[0.0, 1.0]);
+    }
+
+    #[test]
+    fn maps_by_index_to_device_without_layout() {
+        // Windows has no default layout for 10 channels.
+        let map = ChannelMap::for_device(&layout(2), 10, false);
+
+        assert_eq!(map.output_channels(), 10);
+        assert_row(&map, 0, &[1.0, 0.0]);
+        assert_row(&map, 1, &[0.0, 1.0]);
+        for channel in 2..10 {
+            assert_row(&map, channel, &[]);
+        }
+    }
+
+    #[test]
+    fn unassigned_input_channels_map_by_index() {
+        let map = ChannelMap::for_layouts(&[FrontLeft, Unassigned], &layout(2), false);
+
+        assert_row(&map, 0, &[1.0, 0.0]);
+        assert_row(&map, 1, &[0.0, 1.0]);
+    }
+
+    #[test]
+    fn parses_matrix() {
+        let map: ChannelMap = "1, 0; 0.5,0.5; 0,1".parse().unwrap();
+
+        assert_eq!(map.output_channels(), 3);
+        assert_row(&map, 0, &[1.0, 0.0]);
+        assert_row(&map, 1, &[0.5, 0.5]);
+        assert_row(&map, 2, &[0.0, 1.0]);
+
+        let mut input = [0.0; MAX_CHANNELS];
+        input[0] = 0.2;
+        input[1] = 0.4;
+        let output = map.apply(&input);
+        assert!((output[1] - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rejects_invalid_gain() {
+        let error = "1,x;0,1".parse::<ChannelMap>().unwrap_err();
+        assert!(error.contains("invalid gain"), "{}", error);
+
+        assert!("".parse::<ChannelMap>().is_err());
+    }
+
+    #[test]
+    fn rejects_too_many_columns() {
+        let row = ["0"; MAX_CHANNELS + 1].join(",");
+        let error = row.parse::<ChannelMap>().unwrap_err();
+        assert!(error.contains("too many columns"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_too_many_rows() {
+        let matrix = ["1"; MAX_CHANNELS + 1].join(";");
+        let error = matrix.parse::<ChannelMap>().unwrap_err();
+        assert!(error.contains("rows"), "{}", error);
+    }
+}
@@ -1,117 +1,483 @@
-use crate::output_stream::{create_audio_player, AudioPlayer, BufferSample};
-use crate::scream::{ScreamHeader, ScreamHeaderArray, ScreamPacket, SCREAM_PACKET_MAX_SIZE};
-use crate::Args;
-use anyhow::anyhow;
-use byteorder::{ByteOrder, LittleEndian};
-use cpal::traits::{DeviceTrait, HostTrait};
-use std::io::ErrorKind;
-use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
-use std::time::Duration;
-
-const ADDR_ANY: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
-const SCREAM_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 77, 77);
-const SCREAM_MULTICAST_PORT: u16 = 4010;
-
-pub fn start_client(args: &Args) -> anyhow::Result<()> {
-    let device = select_cpal_device(args.output_device.as_ref().map(|s| s.as_str()))?;
-
-    let socket = UdpSocket::bind(SocketAddrV4::new(ADDR_ANY, SCREAM_MULTICAST_PORT))?;
-    socket.join_multicast_v4(&SCREAM_MULTICAST_ADDR, &ADDR_ANY)?;
-    socket.set_read_timeout(Some(Duration::new(1, 0)))?;
-
-    let mut audio_player: Box<Option<AudioPlayer>> = Box::new(None);
-    let mut buf: ScreamPacket = [0u8; SCREAM_PACKET_MAX_SIZE];
-    let mut previous_header: ScreamHeaderArray = [0u8; 5];
-
-    loop {
-        let res = socket.recv_from(&mut buf);
-
-        match &res {
-            Err(e) => {
-                if e.kind() == ErrorKind::TimedOut {
-                    if (&audio_player).is_some() {
-                        println!("No output, stopping audio.");
-                        audio_player = Box::new(None);
-                    }
-                    continue;
-                }
-            }
-
-            _ => (),
-        }
-
-        let (size, _addr) = res?;
-        let header: &ScreamHeaderArray = array_ref![buf, 0, 5];
-        let samples = &buf[5..size];
-
-        if (&audio_player).is_none() || previous_header.as_slice() != header.as_slice() {
-            println!("Output received, starting audio");
-            previous_header = *header;
-            audio_player = Box::new(Some(create_audio_player(&device, header, args)?));
-        }
-
-        let current_audio_player = audio_player.as_mut().as_mut().unwrap();
-
-        let packet_sample_bytes =
-            samples.chunks_exact(header.sample_bytes() * header.channels() as usize);
-
-        for sample_bytes in packet_sample_bytes {
-            let buffer_sample = convert_to_sample(header, sample_bytes);
-
-            match current_audio_player.buffer.push(buffer_sample) {
-                Err(_err) => println!("Buffer overflow"),
-                _ => (),
-            }
-        }
-    }
-}
-
-fn convert_to_f32_sample<const FROM_SIGNED_BIT_INT: isize>(i: f64) -> f32 {
-    if i < 0.0 {
-        (i / (2.0f64.powf(FROM_SIGNED_BIT_INT as f64 - 1.0))) as f32
-    } else {
-        (i / (2.0f64.powf(FROM_SIGNED_BIT_INT as f64 - 1.0) - 1.0)) as f32
-    }
-}
-
-fn convert_to_sample(header: &impl ScreamHeader, sample: &[u8]) -> BufferSample {
-    let mut new_buf = [0.0f32; 10];
-
-    for (i, channel_sample) in sample.chunks(header.sample_bytes()).enumerate() {
-        new_buf[i] = match header.sample_bits() {
-            16 => convert_to_f32_sample::<16>(LittleEndian::read_i16(channel_sample).into()),
-            24 => convert_to_f32_sample::<24>(LittleEndian::read_i24(channel_sample).into()),
-            32 => convert_to_f32_sample::<32>(LittleEndian::read_i32(channel_sample).into()),
-            _ => 0.0,
-        };
-    }
-
-    new_buf
-}
-
-fn output_devices(host: cpal::Host) -> Result<Vec<cpal::Device>, cpal::DevicesError> {
-    let devices = host
-        .devices()?
-        .filter(|d| {
-            // only devices that support output configurations.
-            d.supported_output_configs()
-                .map(|mut x| x.next() != None)
-                .unwrap_or(false)
-        })
-        .collect();
-
-    Ok(devices)
-}
-
-fn select_cpal_device(name: Option<&str>) -> anyhow::Result<cpal::Device> {
-    let host = cpal::default_host();
-
-    let device = match name {
-        Some(n) => output_devices(host)?
-            .into_iter()
-            .find(|d| d.name().map(|name| &name == n).unwrap_or(false)),
-        None => host.default_output_device(),
-    };
-
-    device.ok_or(anyhow!("Could not find audio device"))
-}
+use crate::config::ReceiverConfig;
+use crate::error::{Error, Result};
+use crate::jitter::JitterEstimator;
+use crate::null_sink::NullSink;
+use crate::output_stream::BufferSample;
+use crate::packet_source::{
+    PacketSource, ReceivedPacket, RtpPacketSource, UdpPacketSource, MAX_DATAGRAM_SIZE,
+};
+use crate::packet_stats::{Arrival, ArrivalTracker, InOrder, PacketStats, ReorderBuffer};
+use crate::pcm::PcmSink;
+use crate::recording::RecordingSink;
+use crate::scream::{
+    ScreamFormat, ScreamHeader, ScreamHeaderArray, MAX_CHANNELS, SCREAM_HEADER_SIZE,
+};
+use crate::sink::{AudioSink, CpalSink};
+use crate::source::SourceSelector;
+use crate::source_reader::SourceBuffer;
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Change of the jitter buffer's latency that is worth reporting.
+const JITTER_REPORT_STEP: Duration = Duration::from_millis(5);
+
+/// Most packets repeated in place of lost ones. At half the gain each time,
+/// the last one is down 24 dB; longer gaps are left to the concealer.
+const MAX_REPEATED_PACKETS: usize = 4;
+
+/// Pipeline of a sender that is currently being played.
+struct ActiveSource {
+    addr: IpAddr,
+    format: ScreamFormat,
+    buffer: SourceBuffer,
+    last_seen: Instant,
+    /// Last packet that wasn't all zeros.
+    last_sound: Instant,
+    stats: PacketStats,
+    arrivals: ArrivalTracker,
+    reorder: ReorderBuffer,
+    /// Payload of the previous packet, to spot duplicates and to fill in for
+    /// lost packets.
+    last_payload: Vec<u8>,
+    /// Sets the buffering target, if it adapts to the network.
+    jitter: Option<JitterEstimator>,
+    report_latency: bool,
+    reported_latency: Duration,
+}
+
+impl ActiveSource {
+    fn new(
+        addr: IpAddr,
+        format: ScreamFormat,
+        buffer: SourceBuffer,
+        now: Instant,
+        config: &ReceiverConfig,
+    ) -> ActiveSource {
+        let jitter = config.jitter_buffer.then(|| {
+            JitterEstimator::new(format.sample_rate, config.min_latency, config.max_latency)
+        });
+
+        ActiveSource {
+            addr,
+            format,
+            buffer,
+            last_seen: now,
+            last_sound: now,
+            stats: PacketStats::default(),
+            arrivals: ArrivalTracker::new(format.sample_rate),
+            reorder: ReorderBuffer::default(),
+            last_payload: Vec::new(),
+            jitter,
+            report_latency: config.report_latency,
+            reported_latency: Duration::ZERO,
+        }
+    }
+
+    /// Queues a packet's samples, in sequence order if the packet has a
+    /// sequence number, and keeps track of lost and duplicated packets.
+    fn receive(&mut self, packet: &ReceivedPacket, payload: &[u8]) {
+        let frames = payload.len() / self.format.frame_bytes();
+        self.stats.received += 1;
+        self.update_jitter_buffer(packet, frames);
+
+        let sequence = match packet.sequence {
+            Some(sequence) => sequence,
+            None => {
+                let same_payload = payload == self.last_payload.as_slice();
+                match self
+                    .arrivals
+                    .arrive(packet.received_at, frames, same_payload)
+                {
+                    Arrival::Expected => {}
+                    Arrival::Duplicate => {
+                        self.stats.duplicates += 1;
+                        return;
+                    }
+                    Arrival::Lost(packets) => {
+                        eprintln!("Suspected loss of {} packets from {}", packets, self.addr);
+                        self.stats.add_loss(packets);
+                    }
+                }
+
+                push_samples(&mut self.buffer, &self.format, payload, 1.0);
+                self.last_payload.clear();
+                self.last_payload.extend_from_slice(payload);
+                return;
+            }
+        };
+
+        let addr = self.addr;
+        self.reorder
+            .push(sequence, payload, &mut self.stats, |packet| match packet {
+                InOrder::Payload(payload) => {
+                    push_samples(&mut self.buffer, &self.format, payload, 1.0);
+                    self.last_payload.clear();
+                    self.last_payload.extend_from_slice(payload);
+                }
+                InOrder::Lost(packets) => {
+                    eprintln!("Lost {} packets from {}", packets, addr);
+                    // Repeat the previous packet, quieter every time.
+                    let mut gain = 1.0;
+                    for _ in 0..packets.min(MAX_REPEATED_PACKETS) {
+                        gain *= 0.5;
+                        push_samples(&mut self.buffer, &self.format, &self.last_payload, gain);
+                    }
+                }
+            });
+    }
+
+    /// Moves the buffering target along with the jitter of the arrivals.
+    fn update_jitter_buffer(&mut self, packet: &ReceivedPacket, frames: usize) {
+        let jitter = match &mut self.jitter {
+            Some(jitter) => jitter,
+            None => return,
+        };
+
+        jitter.arrive(packet.received_at, frames, packet.timestamp);
+        let latency = jitter.latency();
+        self.buffer
+            .set_target_fill((latency.as_secs_f64() * self.format.sample_rate as f64) as usize);
+
+        let change = latency.max(self.reported_latency) - latency.min(self.reported_latency);
+        if self.report_latency && change >= JITTER_REPORT_STEP {
+            eprintln!(
+                "Jitter from {}: {:.1} ms, buffering {:.1} ms",
+                self.addr,
+                jitter.jitter().as_secs_f64() * 1000.0,
+                latency.as_secs_f64() * 1000.0
+            );
+            self.reported_latency = latency;
+        }
+    }
+}
+
+impl Drop for ActiveSource {
+    fn drop(&mut self) {
+        eprintln!("Packets from {}: {}", self.addr, self.stats);
+    }
+}
+
+/// Receives Scream packets from a `PacketSource`, decodes them and plays
+/// them on an `AudioSink`.
+pub struct ScreamReceiver {
+    config: ReceiverConfig,
+    stop: Arc<AtomicBool>,
+    packet_source: Box<dyn PacketSource>,
+    sink: Box<dyn AudioSink>,
+    source_selector: SourceSelector,
+    sources: HashMap<IpAddr, ActiveSource>,
+    unsupported_format: Option<ScreamFormat>,
+    buf: Vec<u8>,
+}
+
+/// Builds a `ScreamReceiver`. Without an explicit packet source or sink, a
+/// UDP socket and a cpal output device are set up from the config. An
+/// `rtp_format` in the config receives plain RTP instead of Scream. A
+/// `record_path`, `pcm_output` or `null_output` in the config replaces the
+/// output device with a `RecordingSink`, `PcmSink` or `NullSink`.
+#[derive(Default)]
+pub struct ScreamReceiverBuilder {
+    config: ReceiverConfig,
+    packet_source: Option<Box<dyn PacketSource>>,
+    sink: Option<Box<dyn AudioSink>>,
+}
+
+impl ScreamReceiverBuilder {
+    pub fn config(mut self, config: ReceiverConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn packet_source(mut self, packet_source: impl PacketSource + 'static) -> Self {
+        self.packet_source = Some(Box::new(packet_source));
+        self
+    }
+
+    pub fn sink(mut self, sink: impl AudioSink + 'static) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    pub fn build(self) -> Result<ScreamReceiver> {
+        let sink: Box<dyn AudioSink> = match self.sink {
+            Some(sink) => sink,
+            None if self.config.record_path.is_some() => {
+                Box::new(RecordingSink::new(&self.config)?)
+            }
+            None if self.config.pcm_output.is_some() => Box::new(PcmSink::new(&self.config)?),
+            None if self.config.null_output => Box::new(NullSink::new(&self.config)),
+            None => Box::new(CpalSink::new(&self.config)?),
+        };
+
+        let packet_source: Box<dyn PacketSource> = match self.packet_source {
+            Some(packet_source) => packet_source,
+            None => match self.config.rtp_format {
+                Some(format) => Box::new(RtpPacketSource::new(&self.config, format)?),
+                None => Box::new(UdpPacketSource::new(&self.config)?),
+            },
+        };
+
+        Ok(ScreamReceiver {
+            source_selector: SourceSelector::new(&self.config),
+            config: self.config,
+            stop: Arc::new(AtomicBool::new(false)),
+            packet_source,
+            sink,
+            sources: HashMap::new(),
+            unsupported_format: None,
+            buf: vec![0; MAX_DATAGRAM_SIZE],
+        })
+    }
+}
+
+impl ScreamReceiver {
+    pub fn builder() -> ScreamReceiverBuilder {
+        ScreamReceiverBuilder::default()
+    }
+
+    /// Receives and plays packets until an error occurs or the flag from
+    /// `stop_handle` is set.
+    pub fn run(&mut self) -> Result<()> {
+        while !self.stop.load(Ordering::Relaxed) {
+            self.process_next()?;
+        }
+        Ok(())
+    }
+
+    /// Flag that makes `run` return, e.g. from a Ctrl-C handler. It is
+    /// checked between packets and whenever the packet source times out.
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// Waits for and handles a single packet, or the packet source's
+    /// timeout.
+    pub fn process_next(&mut self) -> Result<()> {
+        match self.sink.poll_error() {
+            Ok(()) => {}
+            Err(e) if e.is_output_failure() => self.recover_sink(e)?,
+            Err(e) => return Err(e),
+        }
+
+        let packet = match self.packet_source.receive(&mut self.buf)? {
+            Some(packet) => packet,
+            None => {
+                self.expire_sources(Instant::now());
+                return Ok(());
+            }
+        };
+
+        let addr = packet.addr;
+        let (format, payload_start) = match packet.format {
+            Some(format) => (format, 0),
+            None if packet.size < SCREAM_HEADER_SIZE => return Ok(()),
+            None => {
+                let header: &ScreamHeaderArray = array_ref![self.buf, 0, SCREAM_HEADER_SIZE];
+                (ScreamFormat::from_header(header), SCREAM_HEADER_SIZE)
+            }
+        };
+        let payload = payload_start..packet.size;
+
+        let now = Instant::now();
+        self.expire_sources(now);
+
+        // Silence doesn't count as activity: it neither starts a source nor
+        // keeps a sender selected, so that a sender that is merely open
+        // doesn't hold on to the output.
+        if let Some(silence_timeout) = self.config.silence_timeout {
+            if self.buf[payload.clone()].iter().all(|&b| b == 0) {
+                self.play_silence(&packet, format, payload, now, silence_timeout);
+                return Ok(());
+            }
+        }
+
+        let accepted = match self.config.mix_sources {
+            true => self.source_selector.is_permitted(addr.ip()),
+            false => self.source_selector.accept(addr.ip(), now),
+        };
+
+        if !accepted {
+            return Ok(());
+        }
+
+        if !format.is_supported() {
+            if self.unsupported_format != Some(format) {
+                eprintln!(
+                    "Unsupported stream format from {}: {} bit, {} channels",
+                    addr.ip(),
+                    format.sample_bits,
+                    format.channels
+                );
+                self.unsupported_format = Some(format);
+            }
+            self.remove_source(addr.ip());
+            return Ok(());
+        }
+
+        let is_new_source = self
+            .sources
+            .get(&addr.ip())
+            .is_none_or(|source| source.format != format);
+
+        if is_new_source {
+            eprintln!(
+                "Output received from {}, starting audio: {} Hz, {} bit, {:?}",
+                addr.ip(),
+                format.sample_rate,
+                format.sample_bits,
+                format.speaker_positions()
+            );
+
+            if !self.config.mix_sources {
+                self.sources.clear();
+            }
+
+            let gain = self.source_selector.gain(addr.ip());
+            let buffer = match self.sink.start_source(addr.ip(), &format, gain) {
+                Ok(buffer) => buffer,
+                Err(e) if e.is_output_failure() => {
+                    self.recover_sink(e)?;
+                    self.sink.start_source(addr.ip(), &format, gain)?
+                }
+                Err(e) => return Err(e),
+            };
+
+            self.sources.insert(
+                addr.ip(),
+                ActiveSource::new(addr.ip(), format, buffer, now, &self.config),
+            );
+        }
+
+        let source = self.sources.get_mut(&addr.ip()).unwrap();
+        source.last_seen = now;
+        source.last_sound = now;
+        source.receive(&packet, &self.buf[payload]);
+
+        Ok(())
+    }
+
+    /// Plays an all-zero packet on a source that is already playing, until
+    /// it has been silent for `silence_timeout`.
+    fn play_silence(
+        &mut self,
+        packet: &ReceivedPacket,
+        format: ScreamFormat,
+        payload: Range<usize>,
+        now: Instant,
+        silence_timeout: Duration,
+    ) {
+        let addr = packet.addr.ip();
+
+        let source = match self.sources.get_mut(&addr) {
+            Some(source) if source.format == format => source,
+            _ => return,
+        };
+
+        if now.duration_since(source.last_sound) < silence_timeout {
+            source.last_seen = now;
+            source.receive(packet, &self.buf[payload]);
+            return;
+        }
+
+        eprintln!("Only silence from {}, stopping it", addr);
+        self.remove_source(addr);
+    }
+
+    /// Stops playing a source, releasing the sink if it was the last one.
+    fn remove_source(&mut self, addr: IpAddr) {
+        if self.sources.remove(&addr).is_some() && self.sources.is_empty() {
+            self.release_sink();
+        }
+    }
+
+    /// Drops sources that haven't sent anything for the idle timeout.
+    fn expire_sources(&mut self, now: Instant) {
+        if self.sources.is_empty() {
+            return;
+        }
+
+        let idle_timeout = self.config.idle_timeout;
+        self.sources
+            .retain(|_, source| now.duration_since(source.last_seen) < idle_timeout);
+
+        if self.sources.is_empty() {
+            self.release_sink();
+        }
+    }
+
+    /// Called when the last source is gone.
+    fn release_sink(&mut self) {
+        if self.config.keep_open {
+            eprintln!("No output, playing silence.");
+        } else {
+            eprintln!("No output, stopping audio.");
+            self.sink.stop();
+        }
+    }
+
+    /// Drops all sources and lets the sink reopen its output. Sources are
+    /// started again as their next packets arrive.
+    fn recover_sink(&mut self, error: Error) -> Result<()> {
+        eprintln!("Audio output failed: {}", error);
+        self.sources.clear();
+        self.sink.recover()
+    }
+}
+
+impl Drop for ScreamReceiver {
+    fn drop(&mut self) {
+        // Sinks may wait for the sources to play out or be written, which
+        // only happens once they are dropped.
+        self.sources.clear();
+    }
+}
+
+fn push_samples(buffer: &mut SourceBuffer, format: &ScreamFormat, samples: &[u8], gain: f32) {
+    let mut dropped = 0;
+    for sample_bytes in samples.chunks_exact(format.frame_bytes()) {
+        let mut buffer_sample = convert_to_sample(format, sample_bytes);
+        for sample in buffer_sample.iter_mut() {
+            *sample *= gain;
+        }
+
+        if buffer.push(buffer_sample).is_err() {
+            dropped += 1;
+        }
+    }
+
+    if dropped > 0 {
+        eprintln!("Buffer overflow, dropped {} samples", dropped);
+    }
+}
+
+fn convert_to_f32_sample<const FROM_SIGNED_BIT_INT: isize>(i: f64) -> f32 {
+    if i < 0.0 {
+        (i / (2.0f64.powf(FROM_SIGNED_BIT_INT as f64 - 1.0))) as f32
+    } else {
+        (i / (2.0f64.powf(FROM_SIGNED_BIT_INT as f64 - 1.0) - 1.0)) as f32
+    }
+}
+
+fn convert_to_sample(header: &impl ScreamHeader, sample: &[u8]) -> BufferSample {
+    let mut new_buf = [0.0f32; MAX_CHANNELS];
+
+    for (i, channel_sample) in sample
+        .chunks(header.sample_bytes())
+        .take(MAX_CHANNELS)
+        .enumerate()
+    {
+        new_buf[i] = match header.sample_bits() {
+            16 => convert_to_f32_sample::<16>(LittleEndian::read_i16(channel_sample).into()),
+            24 => convert_to_f32_sample::<24>(LittleEndian::read_i24(channel_sample).into()),
+            32 => convert_to_f32_sample::<32>(LittleEndian::read_i32(channel_sample).into()),
+            _ => 0.0,
+        };
+    }
+
+    new_buf
+}
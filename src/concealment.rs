@@ -0,0 +1,331 @@
+use crate::output_stream::BufferSample;
+use crate::scream::MAX_CHANNELS;
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Range of waveform periods looked for when repeating, 50 to 400 Hz. That
+/// covers voices and the fundamentals of most instruments.
+const MIN_PERIOD: Duration = Duration::from_micros(2500);
+const MAX_PERIOD: Duration = Duration::from_millis(20);
+
+/// Length of the most recent audio that is compared with earlier audio to
+/// find the period.
+const MATCH_WINDOW: Duration = Duration::from_millis(5);
+
+/// Repetition fades out over this time. Longer gaps end in silence.
+const MAX_REPEAT: Duration = Duration::from_millis(60);
+
+/// The period search runs at roughly this rate, so that its cost doesn't
+/// grow with the stream's sample rate.
+const SEARCH_RATE: u32 = 12000;
+
+/// What is played while the buffer has run dry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Concealment {
+    /// Fade the last sample out.
+    Decay,
+    /// Repeat the last period of the waveform while fading it out, which
+    /// hides gaps of a few packets much better on voice and music.
+    Repeat,
+}
+
+impl FromStr for Concealment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "decay" => Ok(Concealment::Decay),
+            "repeat" => Ok(Concealment::Repeat),
+            _ => Err(format!("unknown concealment '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Concealment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Concealment::Decay => "decay",
+            Concealment::Repeat => "repeat",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Fills gaps in a source's audio and crossfades back into the received
+/// samples once they continue. Works on input frames, before resampling.
+///
+/// It starts out concealing with silence, so the start of a source is faded
+/// in the same way as the end of a gap.
+pub struct Concealer {
+    mode: Concealment,
+    /// Most recent received frames, for finding the waveform to repeat.
+    history: VecDeque<BufferSample>,
+    history_frames: usize,
+    min_period: usize,
+    max_period: usize,
+    match_window: usize,
+    max_repeat: usize,
+    /// Length of the decay and of the crossfade back, in frames.
+    fade_frames: usize,
+    search_step: usize,
+    last: BufferSample,
+
+    concealing: bool,
+    /// Frame decayed from, taken when the gap started.
+    held: BufferSample,
+    /// Length of the repeated waveform, 0 to decay instead.
+    period: usize,
+    concealed_frames: usize,
+    /// Frames of the crossfade back to received audio done so far.
+    resume_position: usize,
+}
+
+impl Concealer {
+    pub fn new(mode: Concealment, sample_rate: u32, fade: Duration) -> Concealer {
+        let frames = |duration: Duration| (duration.as_secs_f64() * sample_rate as f64) as usize;
+
+        Concealer {
+            mode,
+            history: VecDeque::new(),
+            history_frames: frames(MAX_PERIOD) + frames(MATCH_WINDOW),
+            min_period: frames(MIN_PERIOD),
+            max_period: frames(MAX_PERIOD),
+            match_window: frames(MATCH_WINDOW),
+            max_repeat: frames(MAX_REPEAT),
+            fade_frames: frames(fade),
+            search_step: (sample_rate / SEARCH_RATE).max(1) as usize,
+            last: [0.0; MAX_CHANNELS],
+            concealing: true,
+            held: [0.0; MAX_CHANNELS],
+            period: 0,
+            concealed_frames: 0,
+            resume_position: 0,
+        }
+    }
+
+    /// Takes a received frame and returns the frame to play, which is
+    /// crossfaded with the concealment for a while after a gap.
+    pub fn feed(&mut self, frame: &BufferSample) -> BufferSample {
+        let mut frame = *frame;
+
+        if self.concealing {
+            if self.resume_position < self.fade_frames {
+                let weight = (self.resume_position + 1) as f32 / (self.fade_frames + 1) as f32;
+                let concealed = self.conceal_frame();
+                for (sample, concealed) in frame.iter_mut().zip(concealed) {
+                    *sample = *sample * weight + concealed * (1.0 - weight);
+                }
+                self.resume_position += 1;
+            }
+            if self.resume_position >= self.fade_frames {
+                self.concealing = false;
+            }
+        } else if self.mode == Concealment::Repeat {
+            self.history.push_back(frame);
+            if self.history.len() > self.history_frames {
+                self.history.pop_front();
+            }
+        }
+
+        self.last = frame;
+        frame
+    }
+
+    /// Returns a frame to play in place of a missing one.
+    pub fn conceal(&mut self) -> BufferSample {
+        if !self.concealing {
+            self.concealing = true;
+            self.held = self.last;
+            self.period = match self.mode {
+                Concealment::Decay => 0,
+                Concealment::Repeat => self.find_period(),
+            };
+            self.concealed_frames = 0;
+        }
+
+        // Running dry again during the crossfade continues the concealment
+        // where it was and starts the crossfade over.
+        self.resume_position = 0;
+        self.conceal_frame()
+    }
+
+    fn conceal_frame(&mut self) -> BufferSample {
+        let position = self.concealed_frames;
+        self.concealed_frames += 1;
+
+        let (mut frame, length) = match self.period {
+            0 => (self.held, self.fade_frames),
+            period => {
+                let start = self.history.len() - period;
+                (self.history[start + position % period], self.max_repeat)
+            }
+        };
+
+        let gain = if position < length {
+            1.0 - (position + 1) as f32 / length as f32
+        } else {
+            0.0
+        };
+        for sample in frame.iter_mut() {
+            *sample *= gain;
+        }
+
+        frame
+    }
+
+    /// Finds the period of the most recent audio by comparing it with the
+    /// audio before it, on a mono mix at a reduced rate. Returns 0 if there
+    /// isn't enough history yet.
+    fn find_period(&self) -> usize {
+        if self.history.len() < self.history_frames {
+            return 0;
+        }
+
+        let step = self.search_step;
+        let mono: Vec<f32> = self
+            .history
+            .iter()
+            .step_by(step)
+            .map(|frame| frame.iter().sum())
+            .collect();
+
+        let window = self.match_window / step;
+        let max_period = (self.max_period / step).min(mono.len().saturating_sub(window));
+        let recent = &mono[mono.len() - window..];
+        let recent_energy: f32 = recent.iter().map(|s| s * s).sum();
+
+        let mut best = (0, f32::MIN);
+        for period in (self.min_period / step).max(1)..=max_period {
+            let end = mono.len() - period;
+            let earlier = &mono[end - window..end];
+            let correlation: f32 = recent.iter().zip(earlier).map(|(a, b)| a * b).sum();
+            let earlier_energy: f32 = earlier.iter().map(|s| s * s).sum();
+            let score = correlation / (recent_energy * earlier_energy).sqrt().max(f32::EPSILON);
+
+            if score > best.1 {
+                best = (period, score);
+            }
+        }
+
+        best.0 * step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const FADE: Duration = Duration::from_millis(10);
+
+    fn frame(sample: f32) -> BufferSample {
+        let mut frame = [0.0; MAX_CHANNELS];
+        frame[0] = sample;
+        frame[1] = sample;
+        frame
+    }
+
+    /// A 200 Hz sine, 240 frames per period.
+    fn sine(index: usize) -> BufferSample {
+        frame(0.5 * (index as f32 * 200.0 / SAMPLE_RATE as f32 * std::f32::consts::TAU).sin())
+    }
+
+    #[test]
+    fn fades_in_at_the_start() {
+        let mut concealer = Concealer::new(Concealment::Decay, SAMPLE_RATE, FADE);
+
+        let first = concealer.feed(&frame(0.8))[0];
+        assert!(first > 0.0 && first < 0.01, "{}", first);
+        for _ in 0..480 {
+            concealer.feed(&frame(0.8));
+        }
+        assert_eq!(concealer.feed(&frame(0.8)), frame(0.8));
+    }
+
+    #[test]
+    fn decays_to_silence() {
+        let mut concealer = Concealer::new(Concealment::Decay, SAMPLE_RATE, FADE);
+        for _ in 0..1000 {
+            concealer.feed(&frame(0.8));
+        }
+
+        // The last frame fades out over the fade time, 480 frames.
+        let mut previous = 0.8;
+        for _ in 0..480 {
+            let concealed = concealer.conceal();
+            assert!(concealed[0] < previous);
+            assert_eq!(concealed[1], concealed[0]);
+            previous = concealed[0];
+        }
+        assert_eq!(previous, 0.0);
+
+        for _ in 0..1000 {
+            assert_eq!(concealer.conceal(), [0.0; MAX_CHANNELS]);
+        }
+    }
+
+    #[test]
+    fn crossfades_back_after_a_gap() {
+        let mut concealer = Concealer::new(Concealment::Decay, SAMPLE_RATE, FADE);
+        for _ in 0..1000 {
+            concealer.feed(&frame(0.8));
+        }
+        for _ in 0..1000 {
+            concealer.conceal();
+        }
+
+        // From silence back up to what is received.
+        let mut previous = 0.0;
+        for _ in 0..480 {
+            let resumed = concealer.feed(&frame(0.8))[0];
+            assert!(resumed > previous);
+            previous = resumed;
+        }
+        assert_eq!(concealer.feed(&frame(0.8)), frame(0.8));
+    }
+
+    #[test]
+    fn repeats_the_last_period() {
+        let mut concealer = Concealer::new(Concealment::Repeat, SAMPLE_RATE, FADE);
+        let received = 2000;
+        for index in 0..received {
+            concealer.feed(&sine(index));
+        }
+
+        // The repetition continues the waveform, fading out.
+        let length = (MAX_REPEAT.as_secs_f64() * SAMPLE_RATE as f64) as usize;
+        for position in 0..length {
+            let concealed = concealer.conceal()[0];
+            let gain = 1.0 - (position + 1) as f32 / length as f32;
+            let expected = sine(received + position)[0] * gain;
+            assert!(
+                (concealed - expected).abs() < 0.02,
+                "frame {}: {} != {}",
+                position,
+                concealed,
+                expected
+            );
+        }
+
+        // Up to the repeat limit, then silence.
+        for _ in 0..1000 {
+            assert_eq!(concealer.conceal(), [0.0; MAX_CHANNELS]);
+        }
+    }
+
+    #[test]
+    fn repeat_decays_without_enough_history() {
+        let mut concealer = Concealer::new(Concealment::Repeat, SAMPLE_RATE, FADE);
+        for _ in 0..600 {
+            concealer.feed(&frame(0.8));
+        }
+
+        // Like decay: silent after the fade time instead of the repeat limit.
+        for _ in 0..480 {
+            concealer.conceal();
+        }
+        assert_eq!(concealer.conceal(), [0.0; MAX_CHANNELS]);
+    }
+}
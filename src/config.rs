@@ -0,0 +1,253 @@
+use crate::channel_map::ChannelMap;
+use crate::concealment::Concealment;
+use crate::pcm::PcmEncoding;
+use crate::resampler::ResampleQuality;
+use crate::rtp::RtpFormat;
+use crate::socket::{SCREAM_MULTICAST_ADDR, SCREAM_MULTICAST_PORT};
+use crate::source::{SourceGain, SourcePolicy};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+/// Settings for a `ScreamReceiver`. `ReceiverConfig::default()` matches the
+/// defaults of the command line interface.
+#[derive(Debug, Clone)]
+pub struct ReceiverConfig {
+    /// Number of samples to keep buffered. Clock drift is corrected by
+    /// resampling slightly to keep the buffer at this level.
+    pub samples_buffered: usize,
+    /// Amount of audio to keep buffered as a time, which takes the place of
+    /// `samples_buffered` so that the latency doesn't depend on the stream's
+    /// sample rate.
+    pub target_latency: Option<Duration>,
+    /// Periodically print the measured latency of every source.
+    pub report_latency: bool,
+    /// Adapt the amount of audio buffered to the network jitter, between
+    /// `min_latency` and `max_latency`, instead of keeping it fixed.
+    pub jitter_buffer: bool,
+    /// Least amount of audio the jitter buffer keeps.
+    pub min_latency: Duration,
+    /// Most audio the jitter buffer keeps.
+    pub max_latency: Duration,
+    /// After rebuffering or skipping ahead, playback resumes once the buffer
+    /// is within this factor of the target.
+    pub normal_playback_threshold: f32,
+    /// Rebuffer if the buffer falls below this fraction of the target.
+    pub slower_playback_threshold: f32,
+    /// Skip ahead if the buffer grows beyond this multiple of the target.
+    pub faster_playback_threshold: f32,
+    /// Largest playback speed adjustment used to correct clock drift, in
+    /// parts per million.
+    pub max_drift_correction_ppm: f64,
+
+    /// Name of the audio host, e.g. "ALSA" or "JACK". `None` uses the
+    /// platform's default host.
+    pub host: Option<String>,
+    /// Output devices to play on, by name, index or part of the name. Empty
+    /// uses the host's default device.
+    pub output_devices: Vec<String>,
+    /// Play on the default device while a configured device is missing.
+    pub fallback_to_default_device: bool,
+    /// How often to look for the output device again after it failed.
+    pub device_retry_interval: Duration,
+    /// Number of channels to open the output device with. `None` uses the
+    /// stream's channel count if the device supports it.
+    pub output_channels: Option<u16>,
+    /// Routing matrix from stream channels to output channels. `None` maps
+    /// by speaker position.
+    pub channel_map: Option<ChannelMap>,
+    /// Feed surround speakers from the front channels when the stream has
+    /// fewer channels than the output device.
+    pub upmix: bool,
+    /// Resampler used when the output device doesn't support the stream's
+    /// sample rate.
+    pub resample_quality: ResampleQuality,
+    /// Length of the gain ramps when playback starts, stops or runs dry, and
+    /// before the output is closed. Zero switches abruptly.
+    pub fade: Duration,
+    /// What to play while the buffer has run dry.
+    pub concealment: Concealment,
+
+    /// Record to files instead of playing. See `RecordingSink` for the
+    /// placeholders the path can contain.
+    pub record_path: Option<String>,
+    /// Start a new recording after this much digital silence. `None` keeps
+    /// everything in one file per stream.
+    pub record_split_silence: Option<Duration>,
+
+    /// Write raw PCM to this file or named pipe instead of playing, "-" for
+    /// stdout.
+    pub pcm_output: Option<String>,
+    /// Sample encoding of the PCM output.
+    pub pcm_encoding: PcmEncoding,
+    /// Start the PCM output with a WAV header describing the stream.
+    pub pcm_wav_header: bool,
+    /// Discard the audio at real-time speed instead of playing it.
+    pub null_output: bool,
+
+    /// Multicast group the Scream sender transmits to.
+    pub multicast_group: Ipv4Addr,
+    /// UDP port to receive on, in multicast and unicast mode.
+    pub port: u16,
+    /// IPv4 address or name of the network interface to receive on.
+    pub interface: Option<String>,
+    /// Receive packets sent directly to this host instead of joining the
+    /// multicast group.
+    pub unicast: bool,
+    /// Expect every packet to start with an RTP header, whose sequence
+    /// number is used to reorder packets and to conceal lost ones.
+    pub scream_over_rtp: bool,
+    /// Receive a plain RTP stream in this format instead of Scream. See
+    /// `SdpSession::configure` to take it from an SDP file.
+    pub rtp_format: Option<RtpFormat>,
+
+    /// Only accept packets from these senders. Empty accepts every sender
+    /// not in `deny_source`.
+    pub allow_source: Vec<IpAddr>,
+    /// Ignore packets from these senders, even if they are allowed.
+    pub deny_source: Vec<IpAddr>,
+    /// Which sender to play when several are transmitting and
+    /// `mix_sources` is off.
+    pub source_policy: SourcePolicy,
+    /// Sender to play with `SourcePolicy::Pick`.
+    pub source: Option<IpAddr>,
+    /// Play all active senders at once instead of picking one.
+    pub mix_sources: bool,
+    /// Gain applied to the listed senders. Others play at unity gain.
+    pub source_gain: Vec<SourceGain>,
+
+    /// How long a sender can go without sending before it is considered
+    /// gone and its source is stopped.
+    pub idle_timeout: Duration,
+    /// Keep the output open, playing silence, when no sender is left.
+    /// Otherwise the output is closed and reopened when a sender starts.
+    pub keep_open: bool,
+    /// Stop a source that has sent nothing but zeros for this long, as if it
+    /// were idle. Silent packets never start a source. `None` plays silence
+    /// like any other audio.
+    pub silence_timeout: Option<Duration>,
+}
+
+impl Default for ReceiverConfig {
+    fn default() -> Self {
+        ReceiverConfig {
+            samples_buffered: 2048,
+            target_latency: None,
+            report_latency: false,
+            jitter_buffer: false,
+            min_latency: Duration::from_millis(10),
+            max_latency: Duration::from_millis(200),
+            normal_playback_threshold: 1.1,
+            slower_playback_threshold: 0.5,
+            faster_playback_threshold: 2.0,
+            max_drift_correction_ppm: 500.0,
+            host: None,
+            output_devices: Vec::new(),
+            fallback_to_default_device: false,
+            device_retry_interval: Duration::from_secs(1),
+            output_channels: None,
+            channel_map: None,
+            upmix: false,
+            resample_quality: ResampleQuality::Medium,
+            fade: Duration::from_millis(10),
+            concealment: Concealment::Decay,
+            record_path: None,
+            record_split_silence: Some(Duration::from_secs(5)),
+            pcm_output: None,
+            pcm_encoding: PcmEncoding::S16Le,
+            pcm_wav_header: false,
+            null_output: false,
+            multicast_group: SCREAM_MULTICAST_ADDR,
+            port: SCREAM_MULTICAST_PORT,
+            interface: None,
+            unicast: false,
+            scream_over_rtp: false,
+            rtp_format: None,
+            allow_source: Vec::new(),
+            deny_source: Vec::new(),
+            source_policy: SourcePolicy::First,
+            source: None,
+            mix_sources: false,
+            source_gain: Vec::new(),
+            idle_timeout: Duration::from_secs(1),
+            keep_open: false,
+            silence_timeout: None,
+        }
+    }
+}
+
+impl ReceiverConfig {
+    /// Number of samples to keep buffered for a stream at the given rate.
+    pub fn samples_buffered_for(&self, sample_rate: u32) -> usize {
+        match self.target_latency {
+            Some(latency) => (latency.as_secs_f64() * sample_rate as f64).round() as usize,
+            None => self.samples_buffered,
+        }
+    }
+
+    /// Size of the ring buffer between the receiver and the output, in
+    /// samples, with room to spare above the buffering target.
+    pub fn queue_capacity_for(&self, sample_rate: u32) -> usize {
+        let mut samples_buffered = self.samples_buffered_for(sample_rate);
+        if self.jitter_buffer {
+            let max_latency = (self.max_latency.as_secs_f64() * sample_rate as f64) as usize;
+            samples_buffered = samples_buffered.max(max_latency);
+        }
+        samples_buffered * 10
+    }
+}
+
+/// Settings for sending a Scream stream with `sender::run`.
+/// `SenderConfig::default()` matches the defaults of the `send` subcommand.
+#[derive(Debug, Clone)]
+pub struct SenderConfig {
+    /// Name of the audio host to capture through. `None` uses the
+    /// platform's default host.
+    pub host: Option<String>,
+    /// Input device to capture from, by name, index or part of the name.
+    /// `None` uses the host's default input device.
+    pub input_device: Option<String>,
+    /// Read raw PCM from this file or named pipe instead of capturing, "-"
+    /// for stdin. It is sent at real-time speed.
+    pub pcm_input: Option<String>,
+    /// Sample encoding of the PCM input.
+    pub pcm_encoding: PcmEncoding,
+    /// Sample rate to capture at, or of the PCM input. `None` uses the
+    /// device's default rate, or 48000 Hz for PCM input. Scream can only
+    /// carry multiples of 44100 and 48000 Hz.
+    pub sample_rate: Option<u32>,
+    /// Number of channels to capture, or of the PCM input. `None` uses the
+    /// device's default, or 2 for PCM input.
+    pub channels: Option<u16>,
+    /// Bits per sample sent: 16, 24 or 32.
+    pub sample_bits: u8,
+
+    /// Multicast group to send to.
+    pub multicast_group: Ipv4Addr,
+    /// UDP port to send to, in multicast and unicast mode.
+    pub port: u16,
+    /// IPv4 address or name of the network interface to send from.
+    pub interface: Option<String>,
+    /// Send to this host instead of the multicast group.
+    pub unicast_target: Option<Ipv4Addr>,
+    /// Number of router hops multicast packets may take.
+    pub multicast_ttl: u32,
+}
+
+impl Default for SenderConfig {
+    fn default() -> Self {
+        SenderConfig {
+            host: None,
+            input_device: None,
+            pcm_input: None,
+            pcm_encoding: PcmEncoding::S16Le,
+            sample_rate: None,
+            channels: None,
+            sample_bits: 16,
+            multicast_group: SCREAM_MULTICAST_ADDR,
+            port: SCREAM_MULTICAST_PORT,
+            interface: None,
+            unicast_target: None,
+            multicast_ttl: 1,
+        }
+    }
+}
@@ -0,0 +1,122 @@
+/// Proportional gain, in resampling ratio change per relative buffer fill
+/// error.
+const PROPORTIONAL_GAIN: f64 = 200e-6;
+
+/// Integral gain, in resampling ratio change per relative buffer fill error
+/// and second.
+const INTEGRAL_GAIN: f64 = 20e-6;
+
+/// Time constant of the low pass filter smoothing the buffer fill, which
+/// otherwise jumps by a whole packet whenever one arrives.
+const FILL_SMOOTHING_SECONDS: f64 = 0.5;
+
+/// PI controller that keeps the ring buffer fill at its target by nudging
+/// the resampling ratio. The integral term converges on the clock drift
+/// between the sender and the output device.
+pub struct DriftController {
+    max_correction: f64,
+    integral: f64,
+    smoothed_fill: Option<f64>,
+}
+
+impl DriftController {
+    pub fn new(max_correction_ppm: f64) -> DriftController {
+        DriftController {
+            max_correction: max_correction_ppm * 1e-6,
+            integral: 0.0,
+            smoothed_fill: None,
+        }
+    }
+
+    /// Returns the relative ratio correction to apply, given the current
+    /// buffer fill, the target fill and the time since the previous update.
+    pub fn update(&mut self, fill: usize, target: usize, elapsed_seconds: f64) -> f64 {
+        let smoothing = f64::min(1.0, elapsed_seconds / FILL_SMOOTHING_SECONDS);
+        let smoothed_fill = match self.smoothed_fill {
+            Some(previous) => previous + (fill as f64 - previous) * smoothing,
+            None => fill as f64,
+        };
+        self.smoothed_fill = Some(smoothed_fill);
+
+        let error = (smoothed_fill - target as f64) / target.max(1) as f64;
+
+        // Clamping the integral term keeps it from winding up while the
+        // output is saturated.
+        let integral_limit = self.max_correction / INTEGRAL_GAIN;
+        self.integral =
+            (self.integral + error * elapsed_seconds).clamp(-integral_limit, integral_limit);
+
+        (PROPORTIONAL_GAIN * error + INTEGRAL_GAIN * self.integral)
+            .clamp(-self.max_correction, self.max_correction)
+    }
+
+    /// Forgets the fill history, e.g. after the buffer was drained or
+    /// skipped ahead. The learned drift is kept.
+    pub fn reset_fill(&mut self) {
+        self.smoothed_fill = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: usize = 2048;
+    const PERIOD: f64 = 0.01;
+
+    #[test]
+    fn corrects_towards_target() {
+        let mut controller = DriftController::new(500.0);
+        assert!(controller.update(TARGET + 200, TARGET, PERIOD) > 0.0);
+
+        let mut controller = DriftController::new(500.0);
+        assert!(controller.update(TARGET - 200, TARGET, PERIOD) < 0.0);
+
+        let mut controller = DriftController::new(500.0);
+        assert_eq!(controller.update(TARGET, TARGET, PERIOD), 0.0);
+    }
+
+    #[test]
+    fn clamps_at_the_limit() {
+        let mut controller = DriftController::new(500.0);
+        for _ in 0..1000 {
+            let correction = controller.update(10 * TARGET, TARGET, PERIOD);
+            assert!(correction <= 500e-6);
+        }
+        assert!((controller.update(10 * TARGET, TARGET, PERIOD) - 500e-6).abs() < 1e-12);
+
+        let mut controller = DriftController::new(100.0);
+        for _ in 0..1000 {
+            controller.update(0, TARGET, PERIOD);
+        }
+        assert!((controller.update(0, TARGET, PERIOD) + 100e-6).abs() < 1e-12);
+    }
+
+    #[test]
+    fn remembers_drift_once_back_on_target() {
+        let mut controller = DriftController::new(500.0);
+        // A buffer that keeps growing by 1% of the target, as with a sender
+        // whose clock runs fast.
+        for _ in 0..1000 {
+            controller.update(TARGET + TARGET / 100, TARGET, PERIOD);
+        }
+
+        // The integral term holds on to the correction, also across a
+        // reset of the fill history.
+        controller.reset_fill();
+        let correction = controller.update(TARGET, TARGET, PERIOD);
+        assert!(correction > 0.0 && correction < 500e-6, "{}", correction);
+    }
+
+    #[test]
+    fn smooths_the_fill() {
+        let mut controller = DriftController::new(500.0);
+        controller.update(TARGET, TARGET, PERIOD);
+
+        // A single packet arriving moves the fill a lot, but the smoothed
+        // fill only a little.
+        let jump = controller.update(TARGET + 1000, TARGET, PERIOD);
+        let mut fresh = DriftController::new(500.0);
+        assert!(jump < fresh.update(TARGET + 1000, TARGET, PERIOD) / 10.0);
+    }
+}
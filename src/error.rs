@@ -0,0 +1,113 @@
+use thiserror::Error;
+
+/// Errors returned by the receiver and its sinks.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Setting up or reading from the network socket failed.
+    #[error("socket error: {0}")]
+    Socket(#[from] std::io::Error),
+
+    /// The configured network interface has no usable IPv4 address.
+    #[error("could not find an IPv4 address for interface {0}")]
+    InterfaceNotFound(String),
+
+    /// The configured audio host is unknown or not available on this
+    /// platform.
+    #[error("audio host not available: {0}")]
+    HostNotFound(String),
+
+    /// No output device matched the configured name, or there is no default
+    /// output device.
+    #[error("audio device not found: {0}")]
+    DeviceNotFound(String),
+
+    /// Enumerating or querying audio devices failed.
+    #[error("could not query audio devices: {0}")]
+    DeviceQuery(String),
+
+    /// The output does not support the requested stream format.
+    #[error("unsupported format: {0}")]
+    UnsupportedFormat(String),
+
+    /// The output stream could not be created or started.
+    #[error("could not build output stream: {0}")]
+    StreamBuild(String),
+
+    /// A running output stream failed, e.g. because the device went away.
+    #[error("output stream failed: {0}")]
+    StreamRuntime(#[from] cpal::StreamError),
+
+    /// Creating or writing a recording failed.
+    #[error("could not write recording: {0}")]
+    Recording(std::io::Error),
+
+    /// Opening or writing the raw PCM output failed.
+    #[error("could not write PCM output: {0}")]
+    PcmOutput(std::io::Error),
+
+    /// Reading the raw PCM input failed.
+    #[error("could not read PCM input: {0}")]
+    PcmInput(std::io::Error),
+
+    /// The input stream could not be created or started, or failed while
+    /// running.
+    #[error("could not capture audio: {0}")]
+    Capture(String),
+
+    /// An SDP session description doesn't describe a stream that can be
+    /// received.
+    #[error("invalid SDP: {0}")]
+    Sdp(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Whether the error means the output went away, as opposed to e.g. a
+    /// format it will never support.
+    pub(crate) fn is_output_failure(&self) -> bool {
+        matches!(
+            self,
+            Error::DeviceNotFound(_)
+                | Error::DeviceQuery(_)
+                | Error::StreamBuild(_)
+                | Error::StreamRuntime(_)
+        )
+    }
+}
+
+impl From<cpal::BuildStreamError> for Error {
+    fn from(e: cpal::BuildStreamError) -> Self {
+        match e {
+            cpal::BuildStreamError::StreamConfigNotSupported => {
+                Error::UnsupportedFormat(e.to_string())
+            }
+            cpal::BuildStreamError::DeviceNotAvailable => Error::DeviceNotFound(e.to_string()),
+            _ => Error::StreamBuild(e.to_string()),
+        }
+    }
+}
+
+impl From<cpal::PlayStreamError> for Error {
+    fn from(e: cpal::PlayStreamError) -> Self {
+        Error::StreamBuild(e.to_string())
+    }
+}
+
+impl From<cpal::DevicesError> for Error {
+    fn from(e: cpal::DevicesError) -> Self {
+        Error::DeviceQuery(e.to_string())
+    }
+}
+
+impl From<cpal::DefaultStreamConfigError> for Error {
+    fn from(e: cpal::DefaultStreamConfigError) -> Self {
+        Error::DeviceQuery(e.to_string())
+    }
+}
+
+impl From<cpal::SupportedStreamConfigsError> for Error {
+    fn from(e: cpal::SupportedStreamConfigsError) -> Self {
+        Error::DeviceQuery(e.to_string())
+    }
+}
@@ -0,0 +1,439 @@
+use crate::output_stream::BufferSample;
+use crate::pcm::to_int_sample;
+use crate::scream::ScreamFormat;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Most channels a FLAC stream can have.
+pub const FLAC_MAX_CHANNELS: u16 = 8;
+
+/// Widest samples most decoders accept. Wider streams are written with
+/// this many bits.
+const FLAC_MAX_SAMPLE_BITS: u8 = 24;
+
+const BLOCK_SIZE: usize = 4096;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_RICE_PARAMETER: u32 = 14;
+
+/// Offset of the sample rate, which starts the 64 bits of STREAMINFO that
+/// are rewritten with the final sample count.
+const STREAMINFO_SAMPLE_RATE_OFFSET: u64 = 4 + 4 + 10;
+
+/// Writes a FLAC file using fixed predictors and Rice coded residuals. That
+/// gets most of the way to what the reference encoder achieves on typical
+/// material without needing any dependencies.
+pub struct FlacWriter {
+    file: BufWriter<File>,
+    format: ScreamFormat,
+    /// Samples of the block being collected, one list per channel.
+    block: Vec<Vec<i32>>,
+    frame_number: u64,
+    total_samples: u64,
+}
+
+impl FlacWriter {
+    pub fn create(path: &Path, format: &ScreamFormat) -> io::Result<FlacWriter> {
+        if format.channels > FLAC_MAX_CHANNELS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("FLAC can't hold {} channels", format.channels),
+            ));
+        }
+
+        let format = ScreamFormat {
+            sample_bits: format.sample_bits.min(FLAC_MAX_SAMPLE_BITS),
+            ..*format
+        };
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let mut header = BitWriter::default();
+        header.write(u32::from_be_bytes(*b"fLaC") as u64, 32);
+        // Last metadata block, STREAMINFO, 34 bytes.
+        header.write(1, 1);
+        header.write(0, 7);
+        header.write(34, 24);
+        header.write(BLOCK_SIZE as u64, 16);
+        header.write(BLOCK_SIZE as u64, 16);
+        // Frame sizes and MD5 signature unknown, sample count filled in by
+        // `finish`.
+        header.write(0, 24);
+        header.write(0, 24);
+        write_stream_parameters(&mut header, &format, 0);
+        for _ in 0..4 {
+            header.write(0, 32);
+        }
+        file.write_all(&header.bytes)?;
+
+        Ok(FlacWriter {
+            file,
+            format,
+            block: vec![Vec::with_capacity(BLOCK_SIZE); format.channels as usize],
+            frame_number: 0,
+            total_samples: 0,
+        })
+    }
+
+    pub fn write_frame(&mut self, frame: &BufferSample) -> io::Result<()> {
+        for (channel, &sample) in self.block.iter_mut().zip(frame.iter()) {
+            channel.push(to_int_sample(sample, self.format.sample_bits));
+        }
+
+        self.total_samples += 1;
+        if self.block[0].len() == BLOCK_SIZE {
+            self.write_block()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the last partial block, fills in the sample count and closes
+    /// the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.write_block()?;
+
+        let mut parameters = BitWriter::default();
+        write_stream_parameters(&mut parameters, &self.format, self.total_samples);
+        self.file
+            .seek(SeekFrom::Start(STREAMINFO_SAMPLE_RATE_OFFSET))?;
+        self.file.write_all(&parameters.bytes)?;
+        self.file.flush()
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let block_size = self.block[0].len();
+        if block_size == 0 {
+            return Ok(());
+        }
+
+        let mut frame = BitWriter::default();
+        // Sync code with fixed block size strategy.
+        frame.write(0xfff8, 16);
+        // Block size in a 16 bit field after the frame number, sample rate
+        // as in STREAMINFO, channels coded independently.
+        frame.write(0b0111, 4);
+        frame.write(0, 4);
+        frame.write(self.format.channels as u64 - 1, 4);
+        frame.write(sample_size_code(self.format.sample_bits), 3);
+        frame.write(0, 1);
+        write_utf8_number(&mut frame, self.frame_number);
+        frame.write(block_size as u64 - 1, 16);
+        let crc = crc8(&frame.bytes);
+        frame.write(crc as u64, 8);
+
+        for channel in &self.block {
+            write_subframe(&mut frame, channel, self.format.sample_bits as u32);
+        }
+
+        frame.align();
+        let crc = crc16(&frame.bytes);
+        frame.write(crc as u64, 16);
+
+        self.file.write_all(&frame.bytes)?;
+        self.frame_number += 1;
+        for channel in self.block.iter_mut() {
+            channel.clear();
+        }
+
+        Ok(())
+    }
+}
+
+/// Sample rate, channels, bits per sample and total samples: 64 bits that
+/// start on a byte boundary in STREAMINFO.
+fn write_stream_parameters(writer: &mut BitWriter, format: &ScreamFormat, total_samples: u64) {
+    writer.write(format.sample_rate as u64, 20);
+    writer.write(format.channels as u64 - 1, 3);
+    writer.write(format.sample_bits as u64 - 1, 5);
+    writer.write(total_samples >> 32, 4);
+    writer.write(total_samples & 0xffff_ffff, 32);
+}
+
+/// Sample size as coded in a frame header, for the 16 and 24 bits written
+/// here. Decoders don't necessarily take it from STREAMINFO instead.
+fn sample_size_code(sample_bits: u8) -> u64 {
+    match sample_bits {
+        16 => 0b100,
+        _ => 0b110,
+    }
+}
+
+fn write_subframe(writer: &mut BitWriter, samples: &[i32], bits: u32) {
+    if samples.iter().all(|&s| s == samples[0]) {
+        writer.write(0b0000_0000, 8);
+        writer.write_signed(samples[0] as i64, bits);
+        return;
+    }
+
+    let mut best: Option<(usize, u32, u64)> = None;
+    let verbatim_bits = samples.len() as u64 * bits as u64;
+
+    for order in 0..=MAX_FIXED_ORDER.min(samples.len() - 1) {
+        let residuals = match fixed_residuals(samples, order) {
+            Some(residuals) => residuals,
+            None => continue,
+        };
+        let (parameter, residual_bits) = rice_parameter(&residuals);
+        let total_bits = order as u64 * bits as u64 + 2 + 4 + 4 + residual_bits;
+
+        if best.is_none_or(|(_, _, best_bits)| total_bits < best_bits) {
+            best = Some((order, parameter, total_bits));
+        }
+    }
+
+    match best {
+        Some((order, parameter, total_bits)) if total_bits < verbatim_bits => {
+            writer.write(0b0001_0000 | (order as u64) << 1, 8);
+            for &sample in &samples[..order] {
+                writer.write_signed(sample as i64, bits);
+            }
+
+            // Rice coding with a 4 bit parameter and a single partition.
+            writer.write(0b00, 2);
+            writer.write(0, 4);
+            writer.write(parameter as u64, 4);
+            for residual in fixed_residuals(samples, order).unwrap() {
+                let value = zigzag(residual);
+                writer.write_unary(value >> parameter);
+                writer.write(value & ((1 << parameter) - 1), parameter);
+            }
+        }
+        _ => {
+            writer.write(0b0000_0010, 8);
+            for &sample in samples {
+                writer.write_signed(sample as i64, bits);
+            }
+        }
+    }
+}
+
+/// Prediction errors of the fixed polynomial predictor of the given order,
+/// or `None` if they don't fit the 32 bits the format allows.
+fn fixed_residuals(samples: &[i32], order: usize) -> Option<Vec<i64>> {
+    let s = |i: usize| samples[i] as i64;
+
+    (order..samples.len())
+        .map(|i| {
+            let residual = match order {
+                0 => s(i),
+                1 => s(i) - s(i - 1),
+                2 => s(i) - 2 * s(i - 1) + s(i - 2),
+                3 => s(i) - 3 * s(i - 1) + 3 * s(i - 2) - s(i - 3),
+                _ => s(i) - 4 * s(i - 1) + 6 * s(i - 2) - 4 * s(i - 3) + s(i - 4),
+            };
+            i32::try_from(residual).ok().map(|r| r as i64)
+        })
+        .collect()
+}
+
+/// Picks the Rice parameter that codes the residuals in the fewest bits.
+fn rice_parameter(residuals: &[i64]) -> (u32, u64) {
+    (0..=MAX_RICE_PARAMETER)
+        .map(|parameter| {
+            let bits = residuals
+                .iter()
+                .map(|&r| (zigzag(r) >> parameter) + 1 + parameter as u64)
+                .sum();
+            (parameter, bits)
+        })
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap()
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Frame numbers are coded like UTF-8, extended to 36 bits.
+fn write_utf8_number(writer: &mut BitWriter, number: u64) {
+    if number < 0x80 {
+        writer.write(number, 8);
+        return;
+    }
+
+    let mut bytes = 2;
+    while number >= 1 << (5 * bytes + 1) {
+        bytes += 1;
+    }
+
+    let leading_ones = (0xff00u64 >> bytes) & 0xff;
+    writer.write(leading_ones | number >> (6 * (bytes - 1)), 8);
+    for i in (0..bytes - 1).rev() {
+        writer.write(0x80 | (number >> (6 * i)) & 0x3f, 8);
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Big-endian bit writer. Values are written with at most 32 bits at a
+/// time.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+
+        self.pending = (self.pending << bits) | (value & ((1 << bits) - 1));
+        self.pending_bits += bits;
+
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+        self.pending &= (1 << self.pending_bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    fn write_unary(&mut self, zeros: u64) {
+        let mut zeros = zeros;
+        while zeros > 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(0, zeros as u32);
+        self.write(1, 1);
+    }
+
+    /// Pads with zeros to the next byte boundary.
+    fn align(&mut self) {
+        if self.pending_bits > 0 {
+            self.write(0, 8 - self.pending_bits);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scream::MAX_CHANNELS;
+
+    fn format(sample_bits: u8, channels: u16) -> ScreamFormat {
+        ScreamFormat {
+            sample_rate: 48000,
+            sample_bits,
+            channels,
+            channel_mask: 0,
+        }
+    }
+
+    /// Writes the frames to a FLAC file and checks that the reference
+    /// decoder gets them back.
+    fn assert_round_trip(format: &ScreamFormat, frames: &[BufferSample]) {
+        let path = std::env::temp_dir().join(format!(
+            "screamreader-flac-{}-{}-{}-{}.flac",
+            std::process::id(),
+            format.sample_bits,
+            format.channels,
+            frames.len()
+        ));
+        let mut writer = FlacWriter::create(&path, format).unwrap();
+        for frame in frames {
+            writer.write_frame(frame).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = claxon::FlacReader::open(&path).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(info.sample_rate, format.sample_rate);
+        assert_eq!(info.channels, format.channels as u32);
+        let sample_bits = format.sample_bits.min(FLAC_MAX_SAMPLE_BITS);
+        assert_eq!(info.bits_per_sample, sample_bits as u32);
+        assert_eq!(info.samples, Some(frames.len() as u64));
+
+        let samples: Vec<i32> = reader.samples().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+        let expected: Vec<i32> = frames
+            .iter()
+            .flat_map(|frame| &frame[..format.channels as usize])
+            .map(|&sample| to_int_sample(sample, sample_bits))
+            .collect();
+        assert!(samples == expected, "decoded samples differ");
+    }
+
+    /// A different tone on every channel, with the fourth one silent. Tones
+    /// are coded with fixed predictors, silence as constant subframes.
+    fn tones(channels: usize, frames: usize) -> Vec<BufferSample> {
+        (0..frames)
+            .map(|i| {
+                let mut frame = [0.0; MAX_CHANNELS];
+                for (channel, sample) in frame.iter_mut().enumerate().take(channels) {
+                    let phase = i as f32 * 220.0 * (channel + 1) as f32 / 48000.0;
+                    if channel != 3 {
+                        *sample = 0.8 * (phase * std::f32::consts::TAU).sin();
+                    }
+                }
+                frame
+            })
+            .collect()
+    }
+
+    /// Full scale noise, which no predictor helps with, so it is written as
+    /// verbatim subframes.
+    fn noise(frames: usize) -> Vec<BufferSample> {
+        let mut state = 0x1234_5678u32;
+        (0..frames)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let mut frame = [0.0; MAX_CHANNELS];
+                frame[0] = (state as i32) as f32 / 2.0f32.powi(31);
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decodes_with_reference_decoder() {
+        for sample_bits in [16, 24, 32] {
+            // Two full blocks, a partial one, and a single sample block that
+            // leaves no room for prediction. 32 bit streams become 24 bit.
+            assert_round_trip(&format(sample_bits, 2), &tones(2, 2 * BLOCK_SIZE + 1000));
+            assert_round_trip(&format(sample_bits, 1), &tones(1, BLOCK_SIZE + 1));
+            assert_round_trip(&format(sample_bits, 1), &noise(BLOCK_SIZE + 10));
+        }
+        assert_round_trip(&format(24, FLAC_MAX_CHANNELS), &tones(8, BLOCK_SIZE + 100));
+    }
+
+    #[test]
+    fn rejects_too_many_channels() {
+        let path = std::env::temp_dir().join("screamreader-flac-channels.flac");
+        assert!(FlacWriter::create(&path, &format(16, FLAC_MAX_CHANNELS + 1)).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::time::{Duration, Instant};
+
+/// Weight of a new measurement in the jitter estimate, as in RTP.
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+
+/// Factor by which the peak delay spread decays per packet, after it has
+/// gone down. With typical packet sizes it halves in about 5 seconds.
+const PEAK_DECAY: f64 = 0.9995;
+
+/// How fast the lowest delay is let go of, per packet, so that a sender
+/// whose clock runs slow doesn't look like growing jitter.
+const BASELINE_RISE: f64 = 0.001;
+
+/// Buffered audio per packet and spread of delays, to leave some margin.
+const LATENCY_MULTIPLE: f64 = 2.0;
+
+/// Estimates network jitter from packet arrival times and derives the
+/// latency that rides it out.
+///
+/// The delay of a packet is measured against the sender's clock, taken to
+/// run at the stream's sample rate, relative to the lowest delay seen. The
+/// sender's clock is the packet's timestamp if it has one, and otherwise
+/// the audio received so far, which runs behind after lost packets. The
+/// buffer has to cover the spread of these delays, so its recent peak sets
+/// the latency. The jitter itself is computed the way RTP receivers compute
+/// interarrival jitter (RFC 3550), for reporting.
+pub struct JitterEstimator {
+    sample_rate: f64,
+    min_latency: Duration,
+    max_latency: Duration,
+    /// Arrival time and length of the previous packet.
+    last: Option<(Instant, usize)>,
+    /// Mean deviation of arrival intervals from packet lengths, in seconds.
+    jitter: f64,
+    start: Option<Instant>,
+    received_frames: u64,
+    /// Last timestamp and how far it is from the first one, in frames.
+    last_timestamp: Option<(u32, i64)>,
+    /// Lowest delay, in seconds, relative to the first packet.
+    baseline: f64,
+    /// Recent maximum of the delay above the baseline, in seconds.
+    peak_spread: f64,
+    /// Length of the most recent packet, in seconds.
+    packet_length: f64,
+}
+
+impl JitterEstimator {
+    pub fn new(sample_rate: u32, min_latency: Duration, max_latency: Duration) -> JitterEstimator {
+        JitterEstimator {
+            sample_rate: sample_rate as f64,
+            min_latency,
+            max_latency: max_latency.max(min_latency),
+            last: None,
+            jitter: 0.0,
+            start: None,
+            received_frames: 0,
+            last_timestamp: None,
+            baseline: 0.0,
+            peak_spread: 0.0,
+            packet_length: 0.0,
+        }
+    }
+
+    /// Records the arrival of a packet with the given number of frames and,
+    /// if the transport has one, timestamp.
+    pub fn arrive(&mut self, received_at: Instant, frames: usize, timestamp: Option<u32>) {
+        if let Some((last_received_at, last_frames)) = self.last {
+            let interval = received_at
+                .saturating_duration_since(last_received_at)
+                .as_secs_f64();
+            let deviation = interval - last_frames as f64 / self.sample_rate;
+            self.jitter += (deviation.abs() - self.jitter) * JITTER_GAIN;
+        }
+        self.last = Some((received_at, frames));
+
+        let start = *self.start.get_or_insert(received_at);
+        let sent = match timestamp {
+            Some(timestamp) => self.unwrap_timestamp(timestamp) as f64,
+            None => self.received_frames as f64,
+        };
+        let delay =
+            received_at.saturating_duration_since(start).as_secs_f64() - sent / self.sample_rate;
+        self.received_frames += frames as u64;
+
+        if delay < self.baseline {
+            self.baseline = delay;
+        } else {
+            self.baseline += (delay - self.baseline) * BASELINE_RISE;
+        }
+
+        self.peak_spread = (delay - self.baseline).max(self.peak_spread * PEAK_DECAY);
+        self.packet_length = frames as f64 / self.sample_rate;
+    }
+
+    /// Position of a timestamp relative to the first one, taking into
+    /// account that timestamps wrap around and packets may be out of order.
+    fn unwrap_timestamp(&mut self, timestamp: u32) -> i64 {
+        let position = match self.last_timestamp {
+            Some((last, position)) => position + timestamp.wrapping_sub(last) as i32 as i64,
+            None => 0,
+        };
+        self.last_timestamp = Some((timestamp, position));
+        position
+    }
+
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter)
+    }
+
+    /// Latency the buffer should hold to ride out the jitter: enough for a
+    /// packet, as they arrive in one piece, and the spread of delays.
+    pub fn latency(&self) -> Duration {
+        let latency = LATENCY_MULTIPLE * (self.packet_length + self.peak_spread);
+        Duration::from_secs_f64(latency).clamp(self.min_latency, self.max_latency)
+    }
+}
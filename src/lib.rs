@@ -0,0 +1,58 @@
+//! Receiver, and sender, for the Scream virtual network sound card
+//! protocol.
+//!
+//! A `ScreamReceiver` reads packets from a `PacketSource`, by default a UDP
+//! socket, decodes them and plays them on an `AudioSink`, by default a cpal
+//! output device:
+//!
+//! ```no_run
+//! use screamreader_rs::{ReceiverConfig, ScreamReceiver};
+//!
+//! let config = ReceiverConfig {
+//!     output_devices: vec!["USB DAC".to_string()],
+//!     ..ReceiverConfig::default()
+//! };
+//!
+//! ScreamReceiver::builder().config(config).build()?.run()?;
+//! # Ok::<(), screamreader_rs::Error>(())
+//! ```
+
+#[macro_use]
+extern crate arrayref;
+
+pub mod channel_map;
+mod client;
+pub mod concealment;
+pub mod config;
+mod drift;
+mod error;
+#[cfg(feature = "flac")]
+mod flac;
+mod jitter;
+pub mod null_sink;
+pub mod output_stream;
+pub mod packet_source;
+mod packet_stats;
+pub mod pcm;
+pub mod recording;
+pub mod resampler;
+pub mod rtp;
+pub mod scream;
+pub mod sender;
+pub mod sink;
+mod socket;
+pub mod source;
+pub mod source_reader;
+mod wav;
+
+pub use client::{ScreamReceiver, ScreamReceiverBuilder};
+pub use config::{ReceiverConfig, SenderConfig};
+pub use error::{Error, Result};
+pub use null_sink::NullSink;
+pub use packet_source::{PacketSource, ReceivedPacket, UdpPacketSource};
+pub use pcm::PcmSink;
+pub use recording::RecordingSink;
+pub use scream::ScreamFormat;
+pub use sender::ScreamSender;
+pub use sink::{AudioSink, CpalSink};
+pub use socket::{SCREAM_MULTICAST_ADDR, SCREAM_MULTICAST_PORT};
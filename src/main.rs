@@ -1,34 +1,415 @@
-#[macro_use]
-extern crate arrayref;
-
-use anyhow;
-use clap::Parser;
-
-mod client;
-mod output_stream;
-mod scream;
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use screamreader_rs::channel_map::ChannelMap;
+use screamreader_rs::concealment::Concealment;
+use screamreader_rs::output_stream::{list_devices, list_hosts};
+use screamreader_rs::pcm::PcmEncoding;
+use screamreader_rs::resampler::ResampleQuality;
+use screamreader_rs::rtp::{RtpFormat, SdpSession};
+use screamreader_rs::sender;
+use screamreader_rs::source::{SourceGain, SourcePolicy};
+use screamreader_rs::{
+    ReceiverConfig, ScreamReceiver, SenderConfig, SCREAM_MULTICAST_ADDR, SCREAM_MULTICAST_PORT,
+};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true)]
 pub struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Number of samples to keep buffered. Clock drift is corrected by
+    /// resampling slightly to keep the buffer at this level.
     #[clap(short, long, value_parser, default_value_t = 2048)]
     samples_buffered: usize,
 
+    /// Amount of audio to keep buffered in milliseconds, instead of a number
+    /// of samples, so that the latency is the same at every sample rate.
+    #[clap(long, value_parser, conflicts_with = "samples-buffered")]
+    target_latency_ms: Option<u64>,
+
+    /// Print the measured latency, from the network buffer to the output
+    /// device, every few seconds.
+    #[clap(long, value_parser)]
+    report_latency: bool,
+
+    /// Adapt the amount of audio buffered to the network jitter, between
+    /// --min-latency-ms and --max-latency-ms.
+    #[clap(long, value_parser)]
+    jitter_buffer: bool,
+
+    /// Least amount of audio the jitter buffer keeps, in milliseconds.
+    #[clap(long, value_parser, default_value_t = 10)]
+    min_latency_ms: u64,
+
+    /// Most audio the jitter buffer keeps, in milliseconds.
+    #[clap(long, value_parser, default_value_t = 200)]
+    max_latency_ms: u64,
+
+    /// After rebuffering or skipping ahead, playback resumes once the buffer
+    /// is within this factor of the target.
     #[clap(long, value_parser, default_value_t = 1.1)]
     normal_playback_threshold: f32,
 
+    /// Rebuffer if the buffer falls below this fraction of the target.
     #[clap(long, value_parser, default_value_t = 0.5)]
     slower_playback_threshold: f32,
 
+    /// Skip ahead if the buffer grows beyond this multiple of the target.
     #[clap(long, value_parser, default_value_t = 2.0)]
     faster_playback_threshold: f32,
 
+    /// Largest playback speed adjustment used to correct clock drift between
+    /// the sender and the output device, in parts per million.
+    #[clap(long, value_parser, default_value_t = 500.0)]
+    max_drift_correction_ppm: f64,
+
+    /// Audio host to play through, e.g. ALSA or JACK. See --list-hosts.
+    #[clap(long, value_parser)]
+    host: Option<String>,
+
+    /// Print the available audio hosts, then exit.
+    #[clap(long, value_parser)]
+    list_hosts: bool,
+
+    /// Output device, given as its name, its index in --list-devices or a
+    /// part of its name. Repeat to play on several devices at once.
+    #[clap(short, long, value_parser)]
+    output_device: Vec<String>,
+
+    /// Print the available output devices and their supported
+    /// configurations, then exit.
+    #[clap(long, value_parser)]
+    list_devices: bool,
+
+    /// Play on the default device while the output device is unavailable,
+    /// e.g. unplugged.
+    #[clap(long, value_parser)]
+    fallback_to_default_device: bool,
+
+    /// How often to look for the output device after it failed, in
+    /// milliseconds.
+    #[clap(long, value_parser, default_value_t = 1000)]
+    device_retry_interval_ms: u64,
+
+    /// Multicast group the Scream sender transmits to.
+    #[clap(long, value_parser, default_value_t = SCREAM_MULTICAST_ADDR)]
+    multicast_group: Ipv4Addr,
+
+    /// UDP port to receive on, in multicast and unicast mode.
+    #[clap(short, long, value_parser, default_value_t = SCREAM_MULTICAST_PORT)]
+    port: u16,
+
+    /// Network interface to receive on, given as an IPv4 address or an
+    /// interface name. Defaults to the interface picked by the OS, or all
+    /// interfaces in unicast mode.
+    #[clap(short, long, value_parser)]
+    interface: Option<String>,
+
+    /// Receive packets sent directly to this host instead of joining the
+    /// multicast group.
+    #[clap(short, long, value_parser)]
+    unicast: bool,
+
+    /// Expect Scream packets wrapped in RTP. Their sequence numbers are used
+    /// to put packets back in order and to conceal lost ones.
+    #[clap(long, value_parser)]
+    scream_over_rtp: bool,
+
+    /// Receive a plain RTP stream instead of Scream, with samples in this
+    /// format, e.g. "L24/48000/2". L16 and L24 are supported.
+    #[clap(long, value_parser, conflicts_with = "scream-over-rtp")]
+    rtp_format: Option<RtpFormat>,
+
+    /// Receive the RTP stream described by this SDP file. Its port and
+    /// multicast group replace --port and --multicast-group.
+    #[clap(long, value_parser, conflicts_with_all = &["scream-over-rtp", "rtp-format"])]
+    rtp_sdp: Option<String>,
+
+    /// Only accept packets from this sender. Can be given multiple times.
+    #[clap(long, value_parser)]
+    allow_source: Vec<IpAddr>,
+
+    /// Ignore packets from this sender. Can be given multiple times.
+    #[clap(long, value_parser)]
+    deny_source: Vec<IpAddr>,
+
+    /// Which sender to play when several are transmitting at once: "first",
+    /// "latest" or "pick".
+    #[clap(long, value_parser, default_value_t = SourcePolicy::First)]
+    source_policy: SourcePolicy,
+
+    /// Sender to play with the "pick" source policy.
+    #[clap(long, value_parser, required_if_eq("source-policy", "pick"))]
+    source: Option<IpAddr>,
+
+    /// Play all active senders at once, mixed together, instead of picking
+    /// one according to the source policy.
+    #[clap(long, value_parser)]
+    mix_sources: bool,
+
+    /// Gain for a sender, as ADDRESS=GAIN. Can be given multiple times.
+    #[clap(long, value_parser)]
+    source_gain: Vec<SourceGain>,
+
+    /// Stop playing a sender after this many milliseconds without packets.
+    #[clap(long, value_parser, default_value_t = 1000)]
+    idle_timeout_ms: u64,
+
+    /// Keep the output device open and play silence when no sender is left,
+    /// instead of closing it until the next sender starts.
+    #[clap(long, value_parser)]
+    keep_open: bool,
+
+    /// Stop playing a sender that has sent only silence for this many
+    /// milliseconds. Silence never starts playback. By default silence is
+    /// played like any other audio.
+    #[clap(long, value_parser)]
+    silence_timeout_ms: Option<u64>,
+
+    /// Number of channels to open the output device with. Defaults to the
+    /// stream's channel count if the device supports it.
+    #[clap(long, value_parser)]
+    output_channels: Option<u16>,
+
+    /// Routing matrix from stream channels to output channels, one row of
+    /// comma separated gains per output channel, rows separated by
+    /// semicolons. For example "1,0;0,1;0.5,0.5".
+    #[clap(long, value_parser)]
+    channel_map: Option<ChannelMap>,
+
+    /// Feed surround speakers from the front channels when the stream has
+    /// fewer channels than the output device.
+    #[clap(long, value_parser)]
+    upmix: bool,
+
+    /// Resampler quality used when the output device does not support the
+    /// stream's sample rate: "fast", "medium" or "high". Higher quality costs
+    /// more CPU.
+    #[clap(long, value_parser, default_value_t = ResampleQuality::Medium)]
+    resample_quality: ResampleQuality,
+
+    /// Fade in and out over this many milliseconds when playback starts,
+    /// stops or runs dry, to avoid clicks. 0 disables fading.
+    #[clap(long, value_parser, default_value_t = 10)]
+    fade_ms: u64,
+
+    /// What to play when packets are missing: "decay" fades the last sample
+    /// out, "repeat" repeats the last waveform period while fading it out,
+    /// which hides short gaps better.
+    #[clap(long, value_parser, default_value_t = Concealment::Decay)]
+    concealment: Concealment,
+
+    /// Record to files instead of playing. "{time}" in the path is replaced
+    /// with the start time in UTC and "{addr}" with the sender's address.
+    /// Paths ending in .flac are recorded as FLAC if built with the flac
+    /// feature, with 32 bit streams reduced to 24 bit, anything else as WAV.
+    #[clap(long, value_parser, conflicts_with = "pcm-output")]
+    record: Option<String>,
+
+    /// Start a new recording file after this many milliseconds of silence.
+    /// 0 never splits.
+    #[clap(long, value_parser, default_value_t = 5000)]
+    record_split_silence_ms: u64,
+
+    /// Write raw interleaved PCM to this file or named pipe instead of
+    /// playing, or to stdout with "-". Status messages go to stderr.
+    #[clap(long, value_parser)]
+    pcm_output: Option<String>,
+
+    /// Sample encoding of the PCM output: "s16le", "s24le" or "f32le".
+    #[clap(long, value_parser, default_value_t = PcmEncoding::S16Le)]
+    pcm_encoding: PcmEncoding,
+
+    /// Start the PCM output with a WAV header, so that tools can pick up the
+    /// stream's format by themselves.
+    #[clap(long, value_parser)]
+    pcm_wav_header: bool,
+
+    /// Don't use an audio device: receive and buffer as usual, but discard
+    /// the audio at real-time speed. For testing and monitoring on machines
+    /// without sound hardware.
+    #[clap(long, value_parser, conflicts_with_all = &["record", "pcm-output"])]
+    null_output: bool,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Capture from an audio input device, or read raw PCM, and send it as a
+    /// Scream stream.
+    Send(SendArgs),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct SendArgs {
+    /// Audio host to capture through, e.g. ALSA or JACK.
+    #[clap(long, value_parser)]
+    host: Option<String>,
+
+    /// Input device to capture from, given as its name, its index among the
+    /// host's input devices or a part of its name.
     #[clap(short, long, value_parser)]
-    output_device: Option<String>,
+    input_device: Option<String>,
+
+    /// Read raw interleaved PCM from this file or named pipe instead of
+    /// capturing, or from stdin with "-".
+    #[clap(long, value_parser, conflicts_with = "input-device")]
+    pcm_input: Option<String>,
+
+    /// Sample encoding of the PCM input: "s16le", "s24le" or "f32le".
+    #[clap(long, value_parser, default_value_t = PcmEncoding::S16Le)]
+    pcm_encoding: PcmEncoding,
+
+    /// Sample rate to capture at, or of the PCM input. Must be a multiple of
+    /// 44100 or 48000. Defaults to the device's rate, or 48000 for PCM input.
+    #[clap(short = 'r', long, value_parser)]
+    sample_rate: Option<u32>,
+
+    /// Number of channels to capture, or of the PCM input. Defaults to the
+    /// device's channel count, or 2 for PCM input.
+    #[clap(short, long, value_parser)]
+    channels: Option<u16>,
+
+    /// Bits per sample in the packets sent: 16, 24 or 32.
+    #[clap(short = 'b', long, value_parser, default_value_t = 16)]
+    sample_bits: u8,
+
+    /// Multicast group to send to.
+    #[clap(long, value_parser, default_value_t = SCREAM_MULTICAST_ADDR)]
+    multicast_group: Ipv4Addr,
+
+    /// UDP port to send to, in multicast and unicast mode.
+    #[clap(short, long, value_parser, default_value_t = SCREAM_MULTICAST_PORT)]
+    port: u16,
+
+    /// Network interface to send from, given as an IPv4 address or an
+    /// interface name.
+    #[clap(long, value_parser)]
+    interface: Option<String>,
+
+    /// Send to this host instead of the multicast group.
+    #[clap(short, long, value_parser)]
+    unicast_target: Option<Ipv4Addr>,
+
+    /// Number of router hops multicast packets may take.
+    #[clap(long, value_parser, default_value_t = 1)]
+    ttl: u32,
+}
+
+impl From<SendArgs> for SenderConfig {
+    fn from(args: SendArgs) -> Self {
+        SenderConfig {
+            host: args.host,
+            input_device: args.input_device,
+            pcm_input: args.pcm_input,
+            pcm_encoding: args.pcm_encoding,
+            sample_rate: args.sample_rate,
+            channels: args.channels,
+            sample_bits: args.sample_bits,
+            multicast_group: args.multicast_group,
+            port: args.port,
+            interface: args.interface,
+            unicast_target: args.unicast_target,
+            multicast_ttl: args.ttl,
+        }
+    }
+}
+
+impl From<Args> for ReceiverConfig {
+    fn from(args: Args) -> Self {
+        ReceiverConfig {
+            samples_buffered: args.samples_buffered,
+            target_latency: args.target_latency_ms.map(Duration::from_millis),
+            report_latency: args.report_latency,
+            jitter_buffer: args.jitter_buffer,
+            min_latency: Duration::from_millis(args.min_latency_ms),
+            max_latency: Duration::from_millis(args.max_latency_ms),
+            normal_playback_threshold: args.normal_playback_threshold,
+            slower_playback_threshold: args.slower_playback_threshold,
+            faster_playback_threshold: args.faster_playback_threshold,
+            max_drift_correction_ppm: args.max_drift_correction_ppm,
+            host: args.host,
+            output_devices: args.output_device,
+            fallback_to_default_device: args.fallback_to_default_device,
+            device_retry_interval: Duration::from_millis(args.device_retry_interval_ms),
+            output_channels: args.output_channels,
+            channel_map: args.channel_map,
+            upmix: args.upmix,
+            resample_quality: args.resample_quality,
+            fade: Duration::from_millis(args.fade_ms),
+            concealment: args.concealment,
+            record_path: args.record,
+            pcm_output: args.pcm_output,
+            pcm_encoding: args.pcm_encoding,
+            pcm_wav_header: args.pcm_wav_header,
+            null_output: args.null_output,
+            record_split_silence: match args.record_split_silence_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            multicast_group: args.multicast_group,
+            port: args.port,
+            interface: args.interface,
+            unicast: args.unicast,
+            scream_over_rtp: args.scream_over_rtp,
+            rtp_format: args.rtp_format,
+            allow_source: args.allow_source,
+            deny_source: args.deny_source,
+            source_policy: args.source_policy,
+            source: args.source,
+            mix_sources: args.mix_sources,
+            source_gain: args.source_gain,
+            idle_timeout: Duration::from_millis(args.idle_timeout_ms),
+            keep_open: args.keep_open,
+            silence_timeout: args.silence_timeout_ms.map(Duration::from_millis),
+        }
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    client::start_client(&args)
+    if let Some(Command::Send(send_args)) = args.command {
+        sender::run(&send_args.into())?;
+        return Ok(());
+    }
+
+    if args.list_hosts {
+        list_hosts();
+        return Ok(());
+    }
+
+    if args.list_devices {
+        list_devices()?;
+        return Ok(());
+    }
+
+    let sdp_path = args.rtp_sdp.clone();
+    let mut config = ReceiverConfig::from(args);
+
+    if let Some(path) = sdp_path {
+        let sdp = fs::read_to_string(&path).with_context(|| format!("could not read {}", path))?;
+        let session = SdpSession::parse(&sdp)?;
+        eprintln!(
+            "Receiving {} on port {} from the SDP file",
+            session.format, session.port
+        );
+        session.configure(&mut config);
+    }
+
+    let mut receiver = ScreamReceiver::builder().config(config).build()?;
+
+    // Leaving through `main` lets the sinks finish what they are writing.
+    let stop = receiver.stop_handle();
+    ctrlc::set_handler(move || stop.store(true, Ordering::Relaxed))
+        .context("could not handle Ctrl-C")?;
+
+    receiver.run()?;
+
+    Ok(())
 }
@@ -0,0 +1,191 @@
+use crate::config::ReceiverConfig;
+use crate::error::Result;
+use crate::output_stream::Mixer;
+use crate::scream::ScreamFormat;
+use crate::sink::AudioSink;
+use crate::source_reader::{SourceBuffer, SourceReader};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the null output takes its share of samples, like the period of
+/// a sound card.
+const PERIOD: Duration = Duration::from_millis(10);
+
+/// Plays to nowhere. Samples go through the same buffering, resampling and
+/// drift correction as with a sound card, but are consumed by a timer at the
+/// stream's nominal rate. Useful on machines without audio hardware.
+pub struct NullSink {
+    config: ReceiverConfig,
+    output: Option<NullOutput>,
+}
+
+impl NullSink {
+    pub fn new(config: &ReceiverConfig) -> NullSink {
+        NullSink {
+            config: config.clone(),
+            output: None,
+        }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn start_source(
+        &mut self,
+        _addr: IpAddr,
+        format: &ScreamFormat,
+        gain: f32,
+    ) -> Result<SourceBuffer> {
+        let reuse_output = match &self.output {
+            Some(output) => self.config.mix_sources || output.format == *format,
+            None => false,
+        };
+
+        if !reuse_output {
+            drop(self.output.take());
+            self.output = Some(NullOutput::start(format, &self.config));
+        }
+
+        let output = self.output.as_ref().unwrap();
+        let (reader, buffer) = SourceReader::new(
+            format,
+            output.channels,
+            output.sample_rate,
+            gain,
+            &self.config,
+        );
+        let _ = output.sources.send(reader);
+
+        Ok(buffer)
+    }
+
+    fn stop(&mut self) {
+        self.output = None;
+    }
+}
+
+/// Timer thread standing in for an output stream. It stops when dropped.
+struct NullOutput {
+    format: ScreamFormat,
+    channels: u16,
+    sample_rate: u32,
+    sources: mpsc::Sender<SourceReader>,
+    running: Arc<AtomicBool>,
+}
+
+impl NullOutput {
+    fn start(format: &ScreamFormat, config: &ReceiverConfig) -> NullOutput {
+        let channels = match (&config.channel_map, config.output_channels) {
+            (Some(channel_map), _) => channel_map.output_channels(),
+            (None, Some(channels)) => channels,
+            (None, None) => format.channels,
+        };
+        let sample_rate = format.sample_rate;
+        eprintln!("Null output: {} Hz, {} channels", sample_rate, channels);
+
+        let (sources, new_sources) = mpsc::channel();
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread_running = running.clone();
+        thread::spawn(move || {
+            let mut mixer = Mixer::new(new_sources, sample_rate, Duration::ZERO);
+            let start = Instant::now();
+            let mut frames_played = 0u64;
+
+            // Frames are counted from the start, so that rounding and late
+            // wakeups don't add up to a clock error.
+            while thread_running.load(Ordering::Relaxed) {
+                thread::sleep(PERIOD);
+
+                let frames_due = (start.elapsed().as_secs_f64() * sample_rate as f64) as u64;
+                let frames = (frames_due - frames_played) as usize;
+                mixer.prepare(frames, Duration::ZERO);
+                for _ in 0..frames {
+                    mixer.next_frame();
+                }
+                frames_played = frames_due;
+            }
+        });
+
+        NullOutput {
+            format: *format,
+            channels,
+            sample_rate,
+            sources,
+            running,
+        }
+    }
+}
+
+impl Drop for NullOutput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scream::MAX_CHANNELS;
+    use std::net::Ipv4Addr;
+
+    fn stereo(sample_rate: u32) -> ScreamFormat {
+        ScreamFormat {
+            sample_rate,
+            sample_bits: 16,
+            channels: 2,
+            channel_mask: 0x3,
+        }
+    }
+
+    /// Pushes frames until the buffer is full, returning how many fit.
+    fn fill(buffer: &mut SourceBuffer, limit: usize) -> usize {
+        (0..limit)
+            .take_while(|_| buffer.push([0.25; MAX_CHANNELS]).is_ok())
+            .count()
+    }
+
+    #[test]
+    fn mixes_sources_at_the_output_rate() {
+        let config = ReceiverConfig {
+            mix_sources: true,
+            samples_buffered: 24000,
+            ..ReceiverConfig::default()
+        };
+        let mut sink = NullSink::new(&config);
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let mut buffer_48k = sink.start_source(addr, &stereo(48000), 1.0).unwrap();
+        let mut buffer_44k = sink.start_source(addr, &stereo(44100), 1.0).unwrap();
+        assert_eq!(sink.output.as_ref().unwrap().sample_rate, 48000);
+
+        // Both sources wait for more than they get until both are filled,
+        // so that they start together. A target of half the capacity then
+        // keeps them from skipping ahead while they are refilled below.
+        let capacity = config.queue_capacity_for(48000);
+        buffer_48k.set_target_fill(capacity);
+        buffer_44k.set_target_fill(capacity);
+
+        let buffered = capacity / 2 - 5000;
+        assert_eq!(fill(&mut buffer_48k, buffered), buffered);
+        assert_eq!(fill(&mut buffer_44k, buffered), buffered);
+        buffer_48k.set_target_fill(capacity / 2);
+        buffer_44k.set_target_fill(capacity / 2);
+        thread::sleep(Duration::from_secs(1));
+        sink.stop();
+        thread::sleep(10 * PERIOD);
+
+        // Both play for the same time, each at its own rate.
+        let played_48k = fill(&mut buffer_48k, capacity) - (capacity - buffered);
+        let played_44k = fill(&mut buffer_44k, capacity) - (capacity - buffered);
+        let ratio = played_44k as f64 / played_48k as f64;
+        assert!(
+            (ratio - 44100.0 / 48000.0).abs() < 0.03,
+            "played {} and {} frames",
+            played_44k,
+            played_48k
+        );
+    }
+}
@@ -1,166 +1,454 @@
-use crate::scream::{ScreamHeader, ScreamHeaderArray};
-use crate::Args;
-use cpal::traits::{DeviceTrait, StreamTrait};
-use ringbuf::RingBuffer;
-
-const MAX_CHANNELS: usize = 10;
-
-#[derive(Debug, Clone)]
-struct NoSamplesInBufferError;
-
-#[derive(PartialEq, Debug, Clone, Copy)]
-enum OutputMode {
-    Stopped,
-    ChuggingAlong,
-    PlaySlower,
-    PlayFaster,
-}
-
-pub type BufferSample = [f32; MAX_CHANNELS];
-
-pub struct AudioPlayer {
-    pub buffer: ringbuf::Producer<BufferSample>,
-    #[allow(dead_code)]
-    stream: cpal::Stream,
-}
-
-pub fn create_audio_player(
-    device: &cpal::Device,
-    header: &ScreamHeaderArray,
-    args: &Args,
-) -> anyhow::Result<AudioPlayer> {
-    let buf = RingBuffer::<BufferSample>::new(args.samples_buffered * 10);
-    let (prod, cons) = buf.split();
-
-    let stream_config = cpal::StreamConfig {
-        buffer_size: cpal::BufferSize::Default,
-        channels: header.channels(),
-        sample_rate: cpal::SampleRate(header.sample_rate()),
-    };
-
-    let stream = match device.default_output_config()?.sample_format() {
-        cpal::SampleFormat::F32 => {
-            build_output_stream::<f32>(&device, &stream_config, cons, args.clone())
-        }
-        cpal::SampleFormat::I16 => {
-            build_output_stream::<i16>(&device, &stream_config, cons, args.clone())
-        }
-        cpal::SampleFormat::U16 => {
-            build_output_stream::<u16>(&device, &stream_config, cons, args.clone())
-        }
-    }?;
-
-    stream.play()?;
-
-    Ok(AudioPlayer {
-        stream: stream,
-        buffer: prod,
-    })
-}
-
-fn get_output_mode(
-    current_output_mode: OutputMode,
-    samples_requested: usize,
-    samples_available: usize,
-    args: &Args,
-) -> OutputMode {
-    if samples_available == 0 {
-        return OutputMode::Stopped;
-    }
-
-    if current_output_mode == OutputMode::Stopped && samples_available > samples_requested {
-        return OutputMode::ChuggingAlong;
-    }
-
-    if samples_available < (samples_requested as f32 * args.slower_playback_threshold) as usize {
-        return OutputMode::PlaySlower;
-    }
-
-    if samples_available > (samples_requested as f32 * args.faster_playback_threshold) as usize {
-        return OutputMode::PlayFaster;
-    }
-
-    let back_to_chug_low = (samples_requested as f32 / args.normal_playback_threshold) as usize;
-    let back_to_chug_high = (samples_requested as f32 * args.normal_playback_threshold) as usize;
-    if back_to_chug_low < samples_available && samples_available < back_to_chug_high {
-        return OutputMode::ChuggingAlong;
-    }
-
-    return current_output_mode;
-}
-
-fn get_sample(
-    output_mode: OutputMode,
-    cons: &mut ringbuf::Consumer<BufferSample>,
-    last_sample: &BufferSample,
-    iteration: i32,
-) -> Result<[f32; 10], NoSamplesInBufferError> {
-    match output_mode {
-        OutputMode::Stopped => Ok(*last_sample),
-        OutputMode::ChuggingAlong => cons.pop().ok_or(NoSamplesInBufferError),
-        OutputMode::PlayFaster => {
-            // pop an extra one
-            cons.pop().ok_or(NoSamplesInBufferError)?;
-            cons.pop().ok_or(NoSamplesInBufferError)
-        }
-        OutputMode::PlaySlower => {
-            // half of the time, return the previous sample instead
-            if iteration % 2 == 0 {
-                Ok(*last_sample)
-            } else {
-                cons.pop().ok_or(NoSamplesInBufferError)
-            }
-        }
-    }
-}
-
-fn build_output_stream<'a, T>(
-    device: &cpal::Device,
-    config: &cpal::StreamConfig,
-    mut cons: ringbuf::Consumer<BufferSample>,
-    args: Args,
-) -> Result<cpal::Stream, cpal::BuildStreamError>
-where
-    T: cpal::Sample,
-{
-    let channels = config.channels as usize;
-    let mut iteration: i32 = 0;
-    let mut output_mode = OutputMode::Stopped;
-    let mut last_sample: BufferSample = [0.0; MAX_CHANNELS];
-
-    device.build_output_stream(
-        &config,
-        move |output: &mut [T], _: &cpal::OutputCallbackInfo| {
-            let samples_requested = output.len() / channels;
-            let necessary_buffer_size = std::cmp::max(args.samples_buffered, samples_requested);
-
-            for frame in output.chunks_mut(channels.into()) {
-                iteration += 1;
-
-                let new_output_mode =
-                    get_output_mode(output_mode, necessary_buffer_size, cons.len(), &args);
-
-                if output_mode != new_output_mode {
-                    println!(
-                        "Output mode changed: {:?}, samples: {}, buffer_size: {}",
-                        new_output_mode,
-                        cons.len(),
-                        necessary_buffer_size
-                    );
-                }
-
-                output_mode = new_output_mode;
-
-                let sample = get_sample(output_mode, &mut cons, &last_sample, iteration)
-                    .unwrap_or(last_sample);
-
-                for (channel, channel_sample) in frame.iter_mut().enumerate() {
-                    *channel_sample = cpal::Sample::from(&sample[channel]);
-                }
-
-                last_sample = sample;
-            }
-        },
-        |_err| println!("Some weird error huh!"),
-    )
-}
+use crate::config::ReceiverConfig;
+use crate::error::{Error, Result};
+use crate::scream::{ScreamFormat, MAX_CHANNELS};
+use crate::source_reader::{SourceBuffer, SourceReader};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+/// Time allowed for a fade out to be played before the stream is closed, on
+/// top of the fade itself.
+const CLOSE_MARGIN: Duration = Duration::from_millis(50);
+
+/// One decoded sample for every channel, as floats in the range -1..1.
+pub type BufferSample = [f32; MAX_CHANNELS];
+
+/// An open output stream that mixes all sources added to it.
+pub struct AudioPlayer {
+    format: ScreamFormat,
+    config: cpal::StreamConfig,
+    sources: mpsc::Sender<SourceReader>,
+    errors: mpsc::Receiver<cpal::StreamError>,
+    closing: Arc<AtomicBool>,
+    fade: Duration,
+    #[allow(dead_code)]
+    stream: cpal::Stream,
+}
+
+impl AudioPlayer {
+    /// Format of the stream the output was configured for.
+    pub fn format(&self) -> &ScreamFormat {
+        &self.format
+    }
+
+    /// Starts playing a new source. Samples pushed into the returned buffer
+    /// are mixed into the output until it is dropped.
+    pub fn add_source(
+        &self,
+        format: &ScreamFormat,
+        gain: f32,
+        config: &ReceiverConfig,
+    ) -> SourceBuffer {
+        let (reader, buffer) = SourceReader::new(
+            format,
+            self.config.channels,
+            self.config.sample_rate.0,
+            gain,
+            config,
+        );
+
+        // The stream only goes away together with the player, so the
+        // receiver is alive as long as `self` is.
+        let _ = self.sources.send(reader);
+
+        buffer
+    }
+
+    /// Fades the output out and closes it, instead of cutting off whatever
+    /// is playing.
+    pub fn close(self) {
+        if !self.fade.is_zero() {
+            self.closing.store(true, Ordering::Relaxed);
+            // The fade still has to make it through the device's buffer.
+            thread::sleep(self.fade + CLOSE_MARGIN);
+        }
+    }
+
+    /// Returns the first error the stream reported since the last call.
+    pub fn poll_error(&self) -> Result<()> {
+        match self.errors.try_recv() {
+            Ok(e) => Err(Error::StreamRuntime(e)),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+pub fn create_audio_player(
+    device: &cpal::Device,
+    format: &ScreamFormat,
+    config: &ReceiverConfig,
+) -> Result<AudioPlayer> {
+    let output_channels = select_output_channels(device, format, config)?;
+    let output_sample_rate = select_output_sample_rate(device, output_channels, format)?;
+
+    let stream_config = cpal::StreamConfig {
+        buffer_size: cpal::BufferSize::Default,
+        channels: output_channels,
+        sample_rate: cpal::SampleRate(output_sample_rate),
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let (error_sender, error_receiver) = mpsc::channel();
+    let mixer = Mixer::new(receiver, output_sample_rate, config.fade);
+    let closing = mixer.closing();
+
+    let stream = match device.default_output_config()?.sample_format() {
+        cpal::SampleFormat::F32 => {
+            build_output_stream::<f32>(device, &stream_config, mixer, error_sender)
+        }
+        cpal::SampleFormat::I16 => {
+            build_output_stream::<i16>(device, &stream_config, mixer, error_sender)
+        }
+        cpal::SampleFormat::U16 => {
+            build_output_stream::<u16>(device, &stream_config, mixer, error_sender)
+        }
+    }?;
+
+    stream.play()?;
+
+    Ok(AudioPlayer {
+        format: *format,
+        config: stream_config,
+        sources: sender,
+        errors: error_receiver,
+        closing,
+        fade: config.fade,
+        stream,
+    })
+}
+
+fn output_devices(host: &cpal::Host) -> Result<Vec<cpal::Device>> {
+    let devices = host
+        .devices()?
+        .filter(|d| {
+            // only devices that support output configurations.
+            d.supported_output_configs()
+                .map(|mut x| x.next().is_some())
+                .unwrap_or(false)
+        })
+        .collect();
+
+    Ok(devices)
+}
+
+/// Opens the audio host with the given name, ignoring case, e.g. "ALSA" or
+/// "JACK". `None` selects the platform's default host.
+pub fn select_host(name: Option<&str>) -> Result<cpal::Host> {
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(cpal::default_host()),
+    };
+
+    let host_id = cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| Error::HostNotFound(name.to_string()))?;
+
+    cpal::host_from_id(host_id).map_err(|e| Error::HostNotFound(format!("{}: {}", name, e)))
+}
+
+/// Finds an output device by exact name, by its index in `--list-devices`,
+/// or by a case-insensitive part of its name. `None` selects the default
+/// device.
+pub fn select_cpal_device(host: &cpal::Host, name: Option<&str>) -> Result<cpal::Device> {
+    let device = match name {
+        Some(n) => find_device(output_devices(host)?, n)?,
+        None => host.default_output_device(),
+    };
+
+    device.ok_or_else(|| Error::DeviceNotFound(name.unwrap_or("default").to_string()))
+}
+
+pub(crate) fn find_device(devices: Vec<cpal::Device>, name: &str) -> Result<Option<cpal::Device>> {
+    let names: Vec<String> = devices
+        .iter()
+        .map(|d| d.name().unwrap_or_default())
+        .collect();
+
+    if let Some(i) = names.iter().position(|n| n == name) {
+        return Ok(devices.into_iter().nth(i));
+    }
+
+    if let Ok(index) = name.parse::<usize>() {
+        return Ok(devices.into_iter().nth(index));
+    }
+
+    let pattern = name.to_lowercase();
+    let matching: Vec<usize> = (0..names.len())
+        .filter(|&i| names[i].to_lowercase().contains(&pattern))
+        .collect();
+
+    match matching[..] {
+        [] => Ok(None),
+        [i] => Ok(devices.into_iter().nth(i)),
+        _ => {
+            let candidates: Vec<&str> = matching.iter().map(|&i| names[i].as_str()).collect();
+            Err(Error::DeviceNotFound(format!(
+                "{} is ambiguous, matches {}",
+                name,
+                candidates.join(", ")
+            )))
+        }
+    }
+}
+
+/// Prints the names of the audio hosts available on this platform.
+pub fn list_hosts() {
+    let default_host = cpal::default_host().id();
+    for host_id in cpal::available_hosts() {
+        if host_id == default_host {
+            println!("{} (default)", host_id.name());
+        } else {
+            println!("{}", host_id.name());
+        }
+    }
+}
+
+/// Prints every host with its output devices and the configurations they
+/// support. Device indices are per host and can be passed to
+/// `select_cpal_device`.
+pub fn list_devices() -> Result<()> {
+    for host_id in cpal::available_hosts() {
+        let host = match cpal::host_from_id(host_id) {
+            Ok(host) => host,
+            Err(e) => {
+                println!("{}: unavailable ({})", host_id.name(), e);
+                continue;
+            }
+        };
+
+        let default_name = host.default_output_device().and_then(|d| d.name().ok());
+        println!("{}:", host_id.name());
+
+        for (index, device) in output_devices(&host)?.iter().enumerate() {
+            let name = device.name().unwrap_or_default();
+            let default = if Some(&name) == default_name.as_ref() {
+                " (default)"
+            } else {
+                ""
+            };
+            println!("  {}: {}{}", index, name, default);
+
+            // Devices tend to report one range per channel count, so the
+            // channel counts are collected per sample rate range and format.
+            let mut configs: Vec<(u32, u32, cpal::SampleFormat, Vec<u16>)> = Vec::new();
+            for range in device.supported_output_configs()? {
+                let key = (
+                    range.min_sample_rate().0,
+                    range.max_sample_rate().0,
+                    range.sample_format(),
+                );
+                match configs.iter_mut().find(|c| (c.0, c.1, c.2) == key) {
+                    Some(config) => config.3.push(range.channels()),
+                    None => configs.push((key.0, key.1, key.2, vec![range.channels()])),
+                }
+            }
+
+            for (min_rate, max_rate, sample_format, channels) in configs {
+                println!(
+                    "       {:?}, {}-{} Hz, {} channels",
+                    sample_format,
+                    min_rate,
+                    max_rate,
+                    format_channel_counts(channels)
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Formats channel counts like "1-8" or "2, 6".
+fn format_channel_counts(mut channels: Vec<u16>) -> String {
+    channels.sort_unstable();
+    channels.dedup();
+
+    let mut parts = Vec::new();
+    let mut i = 0;
+    while i < channels.len() {
+        let mut j = i;
+        while j + 1 < channels.len() && channels[j + 1] == channels[j] + 1 {
+            j += 1;
+        }
+        if j > i + 1 {
+            parts.push(format!("{}-{}", channels[i], channels[j]));
+        } else {
+            parts.extend(channels[i..=j].iter().map(|c| c.to_string()));
+        }
+        i = j + 1;
+    }
+
+    parts.join(", ")
+}
+
+fn supports_output_channels(device: &cpal::Device, channels: u16, sample_rate: u32) -> bool {
+    device
+        .supported_output_configs()
+        .map(|mut configs| {
+            configs.any(|c| {
+                c.channels() == channels
+                    && c.min_sample_rate().0 <= sample_rate
+                    && sample_rate <= c.max_sample_rate().0
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// Picks the number of channels to open the device with: an explicit channel
+/// count or routing matrix wins, then the stream's own channel count if the
+/// device supports it, then whatever the device prefers.
+fn select_output_channels(
+    device: &cpal::Device,
+    format: &ScreamFormat,
+    config: &ReceiverConfig,
+) -> Result<u16> {
+    if let Some(channel_map) = &config.channel_map {
+        return Ok(channel_map.output_channels());
+    }
+
+    if let Some(channels) = config.output_channels {
+        return Ok(channels);
+    }
+
+    if supports_output_channels(device, format.channels, format.sample_rate) {
+        return Ok(format.channels);
+    }
+
+    Ok(device.default_output_config()?.channels())
+}
+
+/// Uses the stream's sample rate if the device supports it, otherwise the
+/// supported rate closest to it. Anything else is left to the resampler.
+fn select_output_sample_rate(
+    device: &cpal::Device,
+    channels: u16,
+    format: &ScreamFormat,
+) -> Result<u32> {
+    let sample_rate = format.sample_rate;
+
+    if supports_output_channels(device, channels, sample_rate) {
+        return Ok(sample_rate);
+    }
+
+    let closest_supported_rate = device
+        .supported_output_configs()?
+        .filter(|c| c.channels() == channels)
+        .map(|c| sample_rate.clamp(c.min_sample_rate().0, c.max_sample_rate().0))
+        .min_by_key(|rate| rate.abs_diff(sample_rate));
+
+    match closest_supported_rate {
+        Some(rate) => Ok(rate),
+        None => Ok(device.default_output_config()?.sample_rate().0),
+    }
+}
+
+/// Mixes the sources of one output. The output calls `prepare` once per
+/// period and then takes that many frames from `next_frame`.
+pub(crate) struct Mixer {
+    new_sources: mpsc::Receiver<SourceReader>,
+    sources: Vec<SourceReader>,
+    sample_rate: u32,
+    closing: Arc<AtomicBool>,
+    fade_frames: usize,
+    fade_position: usize,
+}
+
+impl Mixer {
+    pub fn new(
+        new_sources: mpsc::Receiver<SourceReader>,
+        sample_rate: u32,
+        fade: Duration,
+    ) -> Mixer {
+        let fade_frames = (fade.as_secs_f64() * sample_rate as f64) as usize;
+        Mixer {
+            new_sources,
+            sources: Vec::new(),
+            sample_rate,
+            closing: Arc::new(AtomicBool::new(false)),
+            fade_frames,
+            fade_position: fade_frames,
+        }
+    }
+
+    /// Flag that makes the mixer fade to silence, before the output is
+    /// closed.
+    pub fn closing(&self) -> Arc<AtomicBool> {
+        self.closing.clone()
+    }
+
+    /// Picks up new sources, drops finished ones and gets the rest ready to
+    /// produce `frames` frames.
+    pub fn prepare(&mut self, frames: usize, output_delay: Duration) {
+        self.sources.extend(self.new_sources.try_iter());
+        self.sources.retain(|source| !source.is_finished());
+
+        for source in self.sources.iter_mut() {
+            source.prepare(frames, self.sample_rate, output_delay);
+        }
+    }
+
+    pub fn next_frame(&mut self) -> BufferSample {
+        let mut output_sample: BufferSample = [0.0; MAX_CHANNELS];
+
+        for source in self.sources.iter_mut() {
+            let source_sample = source.next_frame();
+            for (mixed, sample) in output_sample.iter_mut().zip(source_sample) {
+                *mixed += sample;
+            }
+        }
+
+        if self.closing.load(Ordering::Relaxed) {
+            self.fade_position = self.fade_position.saturating_sub(1);
+            let gain = match self.fade_frames {
+                0 => 0.0,
+                fade_frames => self.fade_position as f32 / fade_frames as f32,
+            };
+            for sample in output_sample.iter_mut() {
+                *sample *= gain;
+            }
+        }
+
+        output_sample
+    }
+}
+
+fn build_output_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut mixer: Mixer,
+    errors: mpsc::Sender<cpal::StreamError>,
+) -> std::result::Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: cpal::Sample,
+{
+    let channels = config.channels as usize;
+
+    device.build_output_stream(
+        config,
+        move |output: &mut [T], info: &cpal::OutputCallbackInfo| {
+            let timestamp = info.timestamp();
+            let output_delay = timestamp
+                .playback
+                .duration_since(&timestamp.callback)
+                .unwrap_or_default();
+            mixer.prepare(output.len() / channels, output_delay);
+
+            for frame in output.chunks_mut(channels) {
+                let output_sample = mixer.next_frame();
+
+                for (channel, channel_sample) in frame.iter_mut().enumerate() {
+                    let value = output_sample.get(channel).copied().unwrap_or(0.0);
+                    *channel_sample = cpal::Sample::from(&value);
+                }
+            }
+        },
+        move |err| {
+            eprintln!("Output stream error: {}", err);
+            // The player may already be gone, in which case nobody cares.
+            let _ = errors.send(err);
+        },
+    )
+}
@@ -0,0 +1,180 @@
+use crate::config::ReceiverConfig;
+use crate::error::Result;
+use crate::rtp::{RtpEncoding, RtpFormat, RtpHeader};
+use crate::scream::ScreamFormat;
+use crate::socket::{enable_timestamps, open_socket, recv_from};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Instant;
+
+/// Largest datagram accepted. A buffer of this size is big enough for
+/// every `PacketSource`.
+pub const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// A packet received by a `PacketSource`.
+#[derive(Debug, Clone, Copy)]
+pub struct ReceivedPacket {
+    /// Size of the Scream packet, header included, or of the samples if
+    /// `format` is given.
+    pub size: usize,
+    pub addr: SocketAddr,
+    /// Sequence number, for transports that have one, e.g. Scream wrapped in
+    /// RTP. It increases by one per packet and wraps around.
+    pub sequence: Option<u16>,
+    /// Sending time of the first sample, in samples, for transports that
+    /// have one, e.g. RTP. It wraps around.
+    pub timestamp: Option<u32>,
+    /// Format of the samples, for transports that convey it out of band.
+    /// The packet is then made of little-endian samples, without a Scream
+    /// header.
+    pub format: Option<ScreamFormat>,
+    /// When the packet arrived, as exactly as the source can tell.
+    pub received_at: Instant,
+}
+
+/// Where Scream packets come from.
+pub trait PacketSource {
+    /// Waits for the next packet and copies it, header included, into `buf`,
+    /// which should hold `MAX_DATAGRAM_SIZE` bytes. Returns `None` if nothing
+    /// arrived before the source's timeout.
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<ReceivedPacket>>;
+}
+
+/// Receives Scream packets over UDP, optionally wrapped in RTP.
+pub struct UdpPacketSource {
+    socket: UdpSocket,
+    scream_over_rtp: bool,
+}
+
+impl UdpPacketSource {
+    /// Opens a multicast or unicast socket as given in the config.
+    pub fn new(config: &ReceiverConfig) -> Result<UdpPacketSource> {
+        let source = UdpPacketSource::from_socket(open_socket(config)?);
+        Ok(source.scream_over_rtp(config.scream_over_rtp))
+    }
+
+    /// Uses an already set up socket. It should have a read timeout, so that
+    /// the receiver notices when packets stop arriving.
+    pub fn from_socket(socket: UdpSocket) -> UdpPacketSource {
+        // Without kernel timestamps, packets are timestamped when read.
+        let _ = enable_timestamps(&socket);
+        UdpPacketSource {
+            socket,
+            scream_over_rtp: false,
+        }
+    }
+
+    /// Expects every Scream packet to be preceded by an RTP header, whose
+    /// sequence number is used to put packets back in order and to notice
+    /// lost ones.
+    pub fn scream_over_rtp(mut self, enabled: bool) -> UdpPacketSource {
+        self.scream_over_rtp = enabled;
+        self
+    }
+}
+
+impl PacketSource for UdpPacketSource {
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<ReceivedPacket>> {
+        loop {
+            let (size, addr, received_at) = match recv_from(&self.socket, buf)? {
+                Some(received) => received,
+                None => return Ok(None),
+            };
+
+            let mut packet = ReceivedPacket {
+                size,
+                addr,
+                sequence: None,
+                timestamp: None,
+                format: None,
+                received_at,
+            };
+
+            if !self.scream_over_rtp {
+                return Ok(Some(packet));
+            }
+
+            // Anything that isn't RTP is ignored.
+            let header = match RtpHeader::parse(&buf[..size]) {
+                Some(header) => header,
+                None => continue,
+            };
+
+            buf.copy_within(header.payload_start..header.payload_end, 0);
+            packet.size = header.payload_end - header.payload_start;
+            packet.sequence = Some(header.sequence);
+            return Ok(Some(packet));
+        }
+    }
+}
+
+/// Receives plain RTP audio streams with uncompressed L16 or L24 payloads,
+/// as sent by e.g. AES67 devices, PulseAudio or GStreamer. RTP packets don't
+/// describe their format, so it has to be given, usually from an SDP file.
+pub struct RtpPacketSource {
+    socket: UdpSocket,
+    format: RtpFormat,
+}
+
+impl RtpPacketSource {
+    /// Opens a multicast or unicast socket as given in the config.
+    pub fn new(config: &ReceiverConfig, format: RtpFormat) -> Result<RtpPacketSource> {
+        Ok(RtpPacketSource::from_socket(open_socket(config)?, format))
+    }
+
+    /// Uses an already set up socket. It should have a read timeout, so that
+    /// the receiver notices when packets stop arriving.
+    pub fn from_socket(socket: UdpSocket, format: RtpFormat) -> RtpPacketSource {
+        let _ = enable_timestamps(&socket);
+        RtpPacketSource { socket, format }
+    }
+}
+
+impl PacketSource for RtpPacketSource {
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<ReceivedPacket>> {
+        let format = self.format.scream_format();
+
+        loop {
+            let (size, addr, received_at) = match recv_from(&self.socket, buf)? {
+                Some(received) => received,
+                None => return Ok(None),
+            };
+
+            // Anything that isn't RTP, or is another payload type sent to the
+            // same port, e.g. comfort noise, is ignored.
+            let header = match RtpHeader::parse(&buf[..size]) {
+                Some(header) => header,
+                None => continue,
+            };
+            if self
+                .format
+                .payload_type
+                .is_some_and(|payload_type| payload_type != header.payload_type)
+            {
+                continue;
+            }
+
+            let payload_size = header.payload_end - header.payload_start;
+            let size = payload_size - payload_size % format.frame_bytes();
+            buf.copy_within(header.payload_start..header.payload_start + size, 0);
+            to_little_endian(self.format.encoding, &mut buf[..size]);
+
+            return Ok(Some(ReceivedPacket {
+                size,
+                addr,
+                sequence: Some(header.sequence),
+                timestamp: Some(header.timestamp),
+                format: Some(format),
+                received_at,
+            }));
+        }
+    }
+}
+
+/// Swaps the bytes of big-endian RTP samples in place.
+fn to_little_endian(encoding: RtpEncoding, samples: &mut [u8]) {
+    let sample_bytes = encoding.sample_bits() as usize / 8;
+    for sample in samples.chunks_exact_mut(sample_bytes) {
+        sample.reverse();
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Instant;
+
+/// Number of packets whose arrival lag is watched before deciding that
+/// audio was lost.
+const LOSS_WINDOW: usize = 64;
+
+/// Number of packets held back while waiting for a missing one.
+const REORDER_DEPTH: usize = 4;
+
+/// Sequence numbers this far from the expected one mean the sender started
+/// over.
+const MAX_SEQUENCE_JUMP: u16 = 1000;
+
+/// Number of sequence numbers of lost packets remembered, to tell packets
+/// that arrived too late from duplicates.
+const LOST_HISTORY: usize = 64;
+
+/// Counters for the packets of one source.
+#[derive(Debug, Default, Clone)]
+pub struct PacketStats {
+    pub received: u64,
+    pub lost: u64,
+    /// Runs of consecutive lost packets.
+    pub bursts: u64,
+    pub longest_burst: u64,
+    pub duplicates: u64,
+    /// Packets that arrived after a later one, in time to be played.
+    pub reordered: u64,
+    /// Packets that arrived after they had been given up on.
+    pub late: u64,
+}
+
+impl PacketStats {
+    pub fn add_loss(&mut self, packets: usize) {
+        self.lost += packets as u64;
+        self.bursts += 1;
+        self.longest_burst = self.longest_burst.max(packets as u64);
+    }
+}
+
+impl fmt::Display for PacketStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} received, {} lost in {} bursts (longest {}), {} duplicates, {} reordered, {} late",
+            self.received,
+            self.lost,
+            self.bursts,
+            self.longest_burst,
+            self.duplicates,
+            self.reordered,
+            self.late
+        )
+    }
+}
+
+/// What `ArrivalTracker` makes of a packet.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Arrival {
+    Expected,
+    /// The packet is a copy of the previous one.
+    Duplicate,
+    /// This many packets have recently gone missing.
+    Lost(usize),
+}
+
+/// Notices lost and duplicated packets from their arrival times, for
+/// packets without sequence numbers.
+///
+/// Arrivals are compared with the sender's clock, taken to run at the
+/// stream's sample rate: the lag of a packet is how much later it arrived
+/// than the audio before it implies, and the lowest lag is the baseline.
+/// Jitter delays single packets, but if no packet in a whole window gets
+/// back near the baseline, the audio in between never arrived.
+pub struct ArrivalTracker {
+    sample_rate: f64,
+    start: Option<Instant>,
+    received_frames: u64,
+    /// Lowest lag, in frames.
+    baseline: f64,
+    /// Lowest lag above the baseline in the current window, in frames.
+    window_excess: f64,
+    window_packets: usize,
+}
+
+impl ArrivalTracker {
+    pub fn new(sample_rate: u32) -> ArrivalTracker {
+        ArrivalTracker {
+            sample_rate: sample_rate as f64,
+            start: None,
+            received_frames: 0,
+            baseline: 0.0,
+            window_excess: f64::INFINITY,
+            window_packets: 0,
+        }
+    }
+
+    /// Records a packet of the given number of frames. `same_payload` tells
+    /// whether it is identical to the previous packet, which only makes it a
+    /// duplicate if it also arrived a packet too early.
+    pub fn arrive(&mut self, now: Instant, frames: usize, same_payload: bool) -> Arrival {
+        let start = *self.start.get_or_insert(now);
+        let lag = now.duration_since(start).as_secs_f64() * self.sample_rate
+            - self.received_frames as f64;
+        let excess = lag - self.baseline;
+
+        if same_payload && excess < -(frames as f64) / 2.0 {
+            return Arrival::Duplicate;
+        }
+
+        self.received_frames += frames as u64;
+        if excess < 0.0 {
+            self.baseline = lag;
+        }
+
+        self.window_excess = self.window_excess.min(excess.max(0.0));
+        self.window_packets += 1;
+        if self.window_packets < LOSS_WINDOW {
+            return Arrival::Expected;
+        }
+
+        // Lag that lasted the whole window becomes the new baseline. Below
+        // half a packet that's just the sender's clock running slow.
+        let lost = (self.window_excess / frames as f64).round() as usize;
+        self.baseline += self.window_excess;
+        self.window_excess = f64::INFINITY;
+        self.window_packets = 0;
+
+        match lost {
+            0 => Arrival::Expected,
+            lost => Arrival::Lost(lost),
+        }
+    }
+}
+
+/// A step in playing sequence-numbered packets in order.
+pub enum InOrder<'a> {
+    Payload(&'a [u8]),
+    /// This many packets are missing here.
+    Lost(usize),
+}
+
+/// Puts sequence-numbered packets back in order. Packets ahead of the
+/// expected one are held back until it arrives, or until too many are
+/// waiting, in which case the missing ones are given up on.
+#[derive(Default)]
+pub struct ReorderBuffer {
+    next: Option<u16>,
+    /// Held back packets, ordered by sequence number.
+    pending: Vec<(u16, Vec<u8>)>,
+    lost: VecDeque<u16>,
+}
+
+impl ReorderBuffer {
+    /// Takes a packet and calls `output` with everything that can be played
+    /// now, in order.
+    pub fn push(
+        &mut self,
+        sequence: u16,
+        payload: &[u8],
+        stats: &mut PacketStats,
+        mut output: impl FnMut(InOrder<'_>),
+    ) {
+        let next = *self.next.get_or_insert(sequence);
+        let distance = sequence.wrapping_sub(next) as i16;
+
+        if distance.unsigned_abs() > MAX_SEQUENCE_JUMP {
+            self.pending.clear();
+            self.next = Some(sequence);
+            return self.push(sequence, payload, stats, output);
+        }
+
+        if distance < 0 {
+            match self.lost.iter().position(|&lost| lost == sequence) {
+                Some(index) => {
+                    self.lost.remove(index);
+                    stats.late += 1;
+                }
+                None => stats.duplicates += 1,
+            }
+            return;
+        }
+
+        if distance == 0 {
+            if !self.pending.is_empty() {
+                stats.reordered += 1;
+            }
+            output(InOrder::Payload(payload));
+            self.next = Some(next.wrapping_add(1));
+            self.play_pending(&mut output);
+            return;
+        }
+
+        if self.pending.iter().any(|(pending, _)| *pending == sequence) {
+            stats.duplicates += 1;
+            return;
+        }
+        self.pending.push((sequence, payload.to_vec()));
+        self.pending
+            .sort_by_key(|(pending, _)| pending.wrapping_sub(next));
+
+        if self.pending.len() > REORDER_DEPTH {
+            // Give up on the missing packets.
+            let resume = self.pending[0].0;
+            let lost = resume.wrapping_sub(next);
+            for sequence in 0..lost {
+                self.lost.push_back(next.wrapping_add(sequence));
+                if self.lost.len() > LOST_HISTORY {
+                    self.lost.pop_front();
+                }
+            }
+            stats.add_loss(lost as usize);
+            output(InOrder::Lost(lost as usize));
+            self.next = Some(resume);
+            self.play_pending(&mut output);
+        }
+    }
+
+    fn play_pending(&mut self, output: &mut impl FnMut(InOrder<'_>)) {
+        while let Some(next) = self.next {
+            if self
+                .pending
+                .first()
+                .is_none_or(|(sequence, _)| *sequence != next)
+            {
+                break;
+            }
+            let (_, payload) = self.pending.remove(0);
+            output(InOrder::Payload(&payload));
+            self.next = Some(next.wrapping_add(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Step {
+        Payload(u16),
+        Lost(usize),
+    }
+
+    /// Pushes packets whose payload is their sequence number and returns
+    /// what comes out.
+    fn push_all(
+        reorder: &mut ReorderBuffer,
+        stats: &mut PacketStats,
+        sequences: &[u16],
+    ) -> Vec<Step> {
+        let mut steps = Vec::new();
+        for &sequence in sequences {
+            reorder.push(sequence, &sequence.to_le_bytes(), stats, |packet| {
+                steps.push(match packet {
+                    InOrder::Payload(payload) => {
+                        Step::Payload(u16::from_le_bytes([payload[0], payload[1]]))
+                    }
+                    InOrder::Lost(packets) => Step::Lost(packets),
+                })
+            });
+        }
+        steps
+    }
+
+    #[test]
+    fn plays_packets_in_order() {
+        let mut reorder = ReorderBuffer::default();
+        let mut stats = PacketStats::default();
+
+        let steps = push_all(&mut reorder, &mut stats, &[10, 12, 11, 13]);
+
+        use Step::Payload;
+        assert_eq!(steps, [Payload(10), Payload(11), Payload(12), Payload(13)]);
+        assert_eq!(stats.reordered, 1);
+        assert_eq!(stats.lost, 0);
+    }
+
+    #[test]
+    fn follows_sequence_wrap() {
+        let mut reorder = ReorderBuffer::default();
+        let mut stats = PacketStats::default();
+
+        let steps = push_all(&mut reorder, &mut stats, &[65534, 0, 65535, 1]);
+
+        use Step::Payload;
+        assert_eq!(
+            steps,
+            [Payload(65534), Payload(65535), Payload(0), Payload(1)]
+        );
+        assert_eq!(stats.reordered, 1);
+        assert_eq!(stats.duplicates, 0);
+    }
+
+    #[test]
+    fn gives_up_on_missing_packets() {
+        let mut reorder = ReorderBuffer::default();
+        let mut stats = PacketStats::default();
+
+        // 65535 and 0 never arrive. Up to four later packets wait for them.
+        let steps = push_all(&mut reorder, &mut stats, &[65534, 1, 2, 3, 4]);
+        assert_eq!(steps, [Step::Payload(65534)]);
+
+        let steps = push_all(&mut reorder, &mut stats, &[5]);
+        use Step::{Lost, Payload};
+        assert_eq!(
+            steps,
+            [
+                Lost(2),
+                Payload(1),
+                Payload(2),
+                Payload(3),
+                Payload(4),
+                Payload(5)
+            ]
+        );
+        assert_eq!(stats.lost, 2);
+        assert_eq!(stats.bursts, 1);
+        assert_eq!(stats.longest_burst, 2);
+    }
+
+    #[test]
+    fn tells_late_packets_from_duplicates() {
+        let mut reorder = ReorderBuffer::default();
+        let mut stats = PacketStats::default();
+        push_all(&mut reorder, &mut stats, &[0, 2, 3, 4, 5, 6]);
+        assert_eq!(stats.lost, 1);
+
+        // 1 was given up on, 3 was played, and 8 is still waiting for 7 when
+        // it comes again.
+        let steps = push_all(&mut reorder, &mut stats, &[1, 3, 8, 8]);
+
+        assert!(steps.is_empty());
+        assert_eq!(stats.late, 1);
+        assert_eq!(stats.duplicates, 2);
+
+        // A packet is only late once.
+        push_all(&mut reorder, &mut stats, &[1]);
+        assert_eq!(stats.late, 1);
+        assert_eq!(stats.duplicates, 3);
+    }
+
+    #[test]
+    fn restarts_after_sequence_jump() {
+        let mut reorder = ReorderBuffer::default();
+        let mut stats = PacketStats::default();
+
+        let steps = push_all(&mut reorder, &mut stats, &[100, 101, 30000, 30001]);
+
+        use Step::Payload;
+        assert_eq!(
+            steps,
+            [Payload(100), Payload(101), Payload(30000), Payload(30001)]
+        );
+        assert_eq!(stats.lost, 0);
+    }
+
+    const SAMPLE_RATE: u32 = 48000;
+    const FRAMES: usize = 288;
+
+    /// When the packet with the given index is due at the sender's rate.
+    fn due(start: Instant, index: usize) -> Instant {
+        start + Duration::from_secs_f64((index * FRAMES) as f64 / SAMPLE_RATE as f64)
+    }
+
+    #[test]
+    fn steady_packets_are_not_lost() {
+        let mut tracker = ArrivalTracker::new(SAMPLE_RATE);
+        let start = Instant::now();
+
+        for index in 0..10 * LOSS_WINDOW {
+            // Every tenth packet is held up by most of a packet's length.
+            let mut arrival = due(start, index);
+            if index % 10 == 5 {
+                arrival += Duration::from_millis(5);
+            }
+            assert_eq!(tracker.arrive(arrival, FRAMES, false), Arrival::Expected);
+        }
+    }
+
+    #[test]
+    fn detects_loss_after_a_window() {
+        let mut tracker = ArrivalTracker::new(SAMPLE_RATE);
+        let start = Instant::now();
+
+        // Packets 100 to 102 are missing.
+        let mut losses = Vec::new();
+        for index in (0..100).chain(103..10 * LOSS_WINDOW) {
+            match tracker.arrive(due(start, index), FRAMES, false) {
+                Arrival::Expected => {}
+                arrival => losses.push((index, arrival)),
+            }
+        }
+
+        // The window the gap is in still had packets at the old baseline,
+        // so the loss shows up at the end of the next one.
+        assert_eq!(losses, [(3 * LOSS_WINDOW + 2, Arrival::Lost(3))]);
+    }
+
+    #[test]
+    fn detects_duplicates() {
+        let mut tracker = ArrivalTracker::new(SAMPLE_RATE);
+        let start = Instant::now();
+
+        assert_eq!(
+            tracker.arrive(due(start, 0), FRAMES, false),
+            Arrival::Expected
+        );
+        assert_eq!(
+            tracker.arrive(due(start, 0), FRAMES, true),
+            Arrival::Duplicate
+        );
+        // Identical packets in their own time, e.g. silence, are not.
+        assert_eq!(
+            tracker.arrive(due(start, 1), FRAMES, true),
+            Arrival::Expected
+        );
+        assert_eq!(
+            tracker.arrive(due(start, 2), FRAMES, true),
+            Arrival::Expected
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use crate::config::ReceiverConfig;
+use crate::error::{Error, Result};
+use crate::scream::{ScreamFormat, ScreamHeader};
+use crate::sink::AudioSink;
+use crate::source_reader::{SampleQueue, SourceBuffer};
+use crate::wav::{write_header, WavSpec};
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, ErrorKind, Write};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// How long the writer thread sleeps when it has caught up with the
+/// received samples.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Sample encoding of raw PCM input and output. All encodings are
+/// interleaved and little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcmEncoding {
+    S16Le,
+    S24Le,
+    F32Le,
+}
+
+impl PcmEncoding {
+    pub(crate) fn sample_bytes(self) -> usize {
+        match self {
+            PcmEncoding::S16Le => 2,
+            PcmEncoding::S24Le => 3,
+            PcmEncoding::F32Le => 4,
+        }
+    }
+
+    fn encode(self, sample: f32, out: &mut [u8]) {
+        match self {
+            PcmEncoding::S16Le => LittleEndian::write_i16(out, to_int_sample(sample, 16) as i16),
+            PcmEncoding::S24Le => LittleEndian::write_i24(out, to_int_sample(sample, 24)),
+            PcmEncoding::F32Le => LittleEndian::write_f32(out, sample),
+        }
+    }
+
+    pub(crate) fn decode(self, sample: &[u8]) -> f32 {
+        match self {
+            PcmEncoding::S16Le => from_int_sample(LittleEndian::read_i16(sample).into(), 16),
+            PcmEncoding::S24Le => from_int_sample(LittleEndian::read_i24(sample), 24),
+            PcmEncoding::F32Le => LittleEndian::read_f32(sample),
+        }
+    }
+}
+
+impl FromStr for PcmEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "s16le" => Ok(PcmEncoding::S16Le),
+            "s24le" => Ok(PcmEncoding::S24Le),
+            "f32le" => Ok(PcmEncoding::F32Le),
+            _ => Err(format!("unknown PCM encoding '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for PcmEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PcmEncoding::S16Le => "s16le",
+            PcmEncoding::S24Le => "s24le",
+            PcmEncoding::F32Le => "f32le",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Converts a sample in the range -1..1 back to a signed integer with the
+/// given number of bits. This is the inverse of the conversion applied to
+/// received packets, so 16 and 24 bit streams come out unchanged.
+pub(crate) fn to_int_sample(sample: f32, bits: u8) -> i32 {
+    let sample = sample.clamp(-1.0, 1.0) as f64;
+    let scale = if sample < 0.0 {
+        2.0f64.powi(bits as i32 - 1)
+    } else {
+        2.0f64.powi(bits as i32 - 1) - 1.0
+    };
+
+    (sample * scale).round() as i32
+}
+
+/// Converts a signed integer sample with the given number of bits to the
+/// range -1..1, the same way received packets are converted.
+pub(crate) fn from_int_sample(sample: i32, bits: u8) -> f32 {
+    let scale = if sample < 0 {
+        2.0f64.powi(bits as i32 - 1)
+    } else {
+        2.0f64.powi(bits as i32 - 1) - 1.0
+    };
+
+    (sample as f64 / scale) as f32
+}
+
+/// Writes the received audio as raw interleaved PCM to stdout or a file,
+/// typically a named pipe read by another program, without resampling.
+///
+/// Each time a stream starts, its format is reported on stderr, and with
+/// `ReceiverConfig::pcm_wav_header` a WAV header is written in front of the
+/// samples. The output carries one stream at a time: a newly started source
+/// takes over from the previous one, even when mixing.
+pub struct PcmSink {
+    sources: mpsc::Sender<(ScreamFormat, f32, SampleQueue)>,
+    errors: mpsc::Receiver<io::Error>,
+    config: ReceiverConfig,
+}
+
+impl PcmSink {
+    pub fn new(config: &ReceiverConfig) -> Result<PcmSink> {
+        let path = match config.pcm_output.as_deref() {
+            Some("-") | None => None,
+            Some(path) => Some(path.to_string()),
+        };
+
+        let mut writer = PcmWriter {
+            output: open_output(path.as_deref()).map_err(Error::PcmOutput)?,
+            path,
+            encoding: config.pcm_encoding,
+            wav_header: config.pcm_wav_header,
+            source: None,
+        };
+
+        let (sources, new_sources) = mpsc::channel();
+        let (error_sender, errors) = mpsc::channel();
+        thread::spawn(move || {
+            if let Err(e) = writer.run(new_sources) {
+                let _ = error_sender.send(e);
+            }
+        });
+
+        Ok(PcmSink {
+            sources,
+            errors,
+            config: config.clone(),
+        })
+    }
+}
+
+impl AudioSink for PcmSink {
+    fn start_source(
+        &mut self,
+        _addr: IpAddr,
+        format: &ScreamFormat,
+        gain: f32,
+    ) -> Result<SourceBuffer> {
+        let (queue, buffer) = SampleQueue::new(self.config.queue_capacity_for(format.sample_rate));
+
+        // If the writer is gone, the error it stopped with is picked up by
+        // `poll_error`.
+        let _ = self.sources.send((*format, gain, queue));
+
+        Ok(buffer)
+    }
+
+    fn stop(&mut self) {
+        // The output stays open; the writer waits for the next source.
+    }
+
+    fn poll_error(&mut self) -> Result<()> {
+        match self.errors.try_recv() {
+            Ok(e) => Err(Error::PcmOutput(e)),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+fn open_output(path: Option<&str>) -> io::Result<Box<dyn Write + Send>> {
+    match path {
+        None => Ok(Box::new(io::stdout())),
+        Some(path) => {
+            // Opening a named pipe blocks until there is a reader.
+            eprintln!("Opening {}", path);
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            Ok(Box::new(file))
+        }
+    }
+}
+
+/// Owns the output and writes the current source to it, running on its own
+/// thread.
+struct PcmWriter {
+    output: Box<dyn Write + Send>,
+    /// `None` for stdout.
+    path: Option<String>,
+    encoding: PcmEncoding,
+    wav_header: bool,
+    source: Option<(ScreamFormat, f32, SampleQueue)>,
+}
+
+impl PcmWriter {
+    fn run(
+        &mut self,
+        new_sources: mpsc::Receiver<(ScreamFormat, f32, SampleQueue)>,
+    ) -> io::Result<()> {
+        let mut chunk = Vec::new();
+
+        loop {
+            loop {
+                match new_sources.try_recv() {
+                    Ok(source) => self.start_source(source)?,
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return self.output.flush(),
+                }
+            }
+
+            chunk.clear();
+            if let Some((format, gain, queue)) = &mut self.source {
+                let channels = format.channels as usize;
+                while let Some(frame) = queue.pop() {
+                    encode_frame(&frame[..channels], *gain, self.encoding, &mut chunk);
+                }
+            }
+
+            if chunk.is_empty() {
+                self.output.flush()?;
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+
+            match self.output.write_all(&chunk) {
+                Ok(()) => {}
+                // The reader of a named pipe went away. Wait for the next one
+                // instead of giving up, and drop what was played meanwhile.
+                Err(e) if e.kind() == ErrorKind::BrokenPipe && self.path.is_some() => {
+                    eprintln!("PCM output closed by the reader");
+                    self.output = open_output(self.path.as_deref())?;
+
+                    // Start the new reader with current audio, not with what
+                    // piled up while waiting for it.
+                    if let Some((_, _, queue)) = &mut self.source {
+                        while queue.pop().is_some() {}
+                    }
+                    if let Some(format) = self.source.as_ref().map(|source| source.0) {
+                        self.write_wav_header(&format)?;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn start_source(&mut self, source: (ScreamFormat, f32, SampleQueue)) -> io::Result<()> {
+        let format = source.0;
+        let changed = self
+            .source
+            .as_ref()
+            .is_none_or(|(current, _, _)| *current != format);
+
+        if changed {
+            eprintln!(
+                "PCM output: {}, {} Hz, {} channels",
+                self.encoding, format.sample_rate, format.channels
+            );
+            self.write_wav_header(&format)?;
+        }
+
+        self.source = Some(source);
+        Ok(())
+    }
+
+    fn write_wav_header(&mut self, format: &ScreamFormat) -> io::Result<()> {
+        if !self.wav_header {
+            return Ok(());
+        }
+
+        write_header(&mut self.output, &wav_spec(format, self.encoding), u32::MAX)?;
+        Ok(())
+    }
+}
+
+/// Describes a stream in the given encoding.
+fn wav_spec(format: &ScreamFormat, encoding: PcmEncoding) -> WavSpec {
+    WavSpec {
+        channels: format.channels,
+        sample_rate: format.sample_rate,
+        bits: encoding.sample_bytes() as u16 * 8,
+        float: encoding == PcmEncoding::F32Le,
+        channel_mask: format.channel_mask(),
+    }
+}
+
+fn encode_frame(frame: &[f32], gain: f32, encoding: PcmEncoding, out: &mut Vec<u8>) {
+    let sample_bytes = encoding.sample_bytes();
+    for &sample in frame {
+        let start = out.len();
+        out.resize(start + sample_bytes, 0);
+        encoding.encode(sample * gain, &mut out[start..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_little_endian_samples() {
+        let frame = [0.5, -0.5];
+        let mut bytes = Vec::new();
+
+        encode_frame(&frame, 1.0, PcmEncoding::S16Le, &mut bytes);
+        assert_eq!(bytes, [0x00, 0x40, 0x00, 0xc0]);
+
+        bytes.clear();
+        encode_frame(&frame, 1.0, PcmEncoding::S24Le, &mut bytes);
+        assert_eq!(bytes, [0x00, 0x00, 0x40, 0x00, 0x00, 0xc0]);
+
+        bytes.clear();
+        encode_frame(&frame, 1.0, PcmEncoding::F32Le, &mut bytes);
+        assert_eq!(bytes, [0, 0, 0, 0x3f, 0, 0, 0, 0xbf]);
+    }
+
+    #[test]
+    fn applies_gain_and_clips() {
+        let mut bytes = Vec::new();
+        encode_frame(&[0.5, 1.0], 2.0, PcmEncoding::S16Le, &mut bytes);
+        assert_eq!(bytes, [0xff, 0x7f, 0xff, 0x7f]);
+    }
+
+    #[test]
+    fn decoded_samples_encode_unchanged() {
+        let samples: [&[u8]; 3] = [
+            &[0x00, 0x80, 0xff, 0x7f, 0x01, 0x00, 0x00, 0x00, 0x34, 0x12],
+            &[0x00, 0x00, 0x80, 0xff, 0xff, 0x7f, 0x56, 0x34, 0x12],
+            &[0x00, 0x00, 0x80, 0xbf, 0x00, 0x00, 0x00, 0x3e],
+        ];
+        for (encoding, bytes) in [PcmEncoding::S16Le, PcmEncoding::S24Le, PcmEncoding::F32Le]
+            .into_iter()
+            .zip(samples)
+        {
+            let frame: Vec<f32> = bytes
+                .chunks(encoding.sample_bytes())
+                .map(|sample| encoding.decode(sample))
+                .collect();
+            let mut encoded = Vec::new();
+            encode_frame(&frame, 1.0, encoding, &mut encoded);
+            assert_eq!(encoded, bytes, "{}", encoding);
+        }
+    }
+
+    #[test]
+    fn writes_wav_header_before_frames() {
+        let format = ScreamFormat {
+            sample_rate: 48000,
+            sample_bits: 16,
+            channels: 2,
+            channel_mask: 0x3,
+        };
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, &wav_spec(&format, PcmEncoding::S24Le), u32::MAX).unwrap();
+        for _ in 0..2 {
+            encode_frame(&[0.5, -0.5], 1.0, PcmEncoding::S24Le, &mut bytes);
+        }
+
+        // Samples wider than 16 bits need the extensible header.
+        assert_eq!(bytes.len(), 68 + 2 * 6);
+        assert_eq!(bytes[20..24], [0xfe, 0xff, 2, 0]);
+        assert_eq!(bytes[24..28], 48000u32.to_le_bytes());
+        assert_eq!(bytes[32..36], [6, 0, 24, 0]);
+        assert_eq!(bytes[40..44], [0x3, 0, 0, 0]);
+        assert_eq!(&bytes[60..64], b"data");
+        assert_eq!(bytes[68..74], [0x00, 0x00, 0x40, 0x00, 0x00, 0xc0]);
+        assert_eq!(bytes[68..74], bytes[74..80]);
+
+        let float = wav_spec(&format, PcmEncoding::F32Le);
+        assert!(float.float);
+        assert_eq!(float.bits, 32);
+    }
+}
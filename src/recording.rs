@@ -0,0 +1,349 @@
+use crate::config::ReceiverConfig;
+use crate::error::{Error, Result};
+#[cfg(feature = "flac")]
+use crate::flac::{FlacWriter, FLAC_MAX_CHANNELS};
+use crate::output_stream::BufferSample;
+use crate::scream::ScreamFormat;
+use crate::sink::AudioSink;
+use crate::source_reader::{SampleQueue, SourceBuffer};
+use crate::wav::WavWriter;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a recording thread sleeps when it has caught up with the
+/// received samples.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Writes every source to audio files instead of playing it.
+///
+/// File names come from `ReceiverConfig::record_path`, in which `{time}` is
+/// replaced with the UTC time the file was started and `{addr}` with the
+/// sender's address. Names ending in `.flac` are written as FLAC, everything
+/// else as WAV. A new file is started whenever the stream's format changes,
+/// after the sender went quiet, and after a long enough stretch of silence.
+///
+/// Files are finished once their sources are dropped. `stop` and dropping
+/// the sink wait for that, so sources must be dropped first.
+pub struct RecordingSink {
+    config: ReceiverConfig,
+    recorders: Vec<thread::JoinHandle<()>>,
+    errors_sender: mpsc::Sender<io::Error>,
+    errors: mpsc::Receiver<io::Error>,
+}
+
+impl RecordingSink {
+    pub fn new(config: &ReceiverConfig) -> Result<RecordingSink> {
+        let template = config.record_path.as_deref().unwrap_or_default();
+        if template.is_empty() {
+            return Err(Error::UnsupportedFormat(
+                "no recording path given".to_string(),
+            ));
+        }
+        if is_flac(template) && !cfg!(feature = "flac") {
+            return Err(Error::UnsupportedFormat(
+                "FLAC recording needs the flac feature".to_string(),
+            ));
+        }
+
+        let (errors_sender, errors) = mpsc::channel();
+        Ok(RecordingSink {
+            config: config.clone(),
+            recorders: Vec::new(),
+            errors_sender,
+            errors,
+        })
+    }
+}
+
+impl AudioSink for RecordingSink {
+    fn start_source(
+        &mut self,
+        addr: IpAddr,
+        format: &ScreamFormat,
+        gain: f32,
+    ) -> Result<SourceBuffer> {
+        let (queue, buffer) = SampleQueue::new(self.config.queue_capacity_for(format.sample_rate));
+
+        let mut recorder = Recorder {
+            template: self.config.record_path.clone().unwrap_or_default(),
+            addr,
+            format: *format,
+            gain,
+            split_after_frames: self
+                .config
+                .record_split_silence
+                .map(|gap| (gap.as_secs_f64() * format.sample_rate as f64) as usize),
+            file: None,
+            silent_frames: 0,
+        };
+
+        // Without silence splitting the file is opened right away, so that a
+        // bad path is reported to the caller instead of the thread.
+        if recorder.split_after_frames.is_none() {
+            recorder.open_file().map_err(Error::Recording)?;
+        }
+
+        let errors = self.errors_sender.clone();
+        self.recorders.retain(|recorder| !recorder.is_finished());
+        self.recorders.push(thread::spawn(move || {
+            if let Err(e) = recorder.run(queue) {
+                let _ = errors.send(e);
+            }
+        }));
+
+        Ok(buffer)
+    }
+
+    fn stop(&mut self) {
+        // All sources are gone, so the recorders are finishing their files.
+        for recorder in self.recorders.drain(..) {
+            let _ = recorder.join();
+        }
+    }
+
+    fn poll_error(&mut self) -> Result<()> {
+        match self.errors.try_recv() {
+            Ok(e) => Err(Error::Recording(e)),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl Drop for RecordingSink {
+    fn drop(&mut self) {
+        self.stop();
+        while let Ok(e) = self.errors.try_recv() {
+            eprintln!("Could not write recording: {}", e);
+        }
+    }
+}
+
+enum AudioFile {
+    Wav(WavWriter),
+    #[cfg(feature = "flac")]
+    Flac(FlacWriter),
+}
+
+impl AudioFile {
+    fn create(path: &Path, format: &ScreamFormat) -> io::Result<AudioFile> {
+        #[cfg(feature = "flac")]
+        if is_flac(&path.to_string_lossy()) {
+            return Ok(AudioFile::Flac(FlacWriter::create(path, format)?));
+        }
+
+        Ok(AudioFile::Wav(WavWriter::create(path, format)?))
+    }
+
+    fn write_frame(&mut self, frame: &BufferSample) -> io::Result<()> {
+        match self {
+            AudioFile::Wav(writer) => writer.write_frame(frame),
+            #[cfg(feature = "flac")]
+            AudioFile::Flac(writer) => writer.write_frame(frame),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            AudioFile::Wav(writer) => writer.finish(),
+            #[cfg(feature = "flac")]
+            AudioFile::Flac(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Writes one source's samples, running on its own thread.
+struct Recorder {
+    template: String,
+    addr: IpAddr,
+    format: ScreamFormat,
+    gain: f32,
+    split_after_frames: Option<usize>,
+    file: Option<(AudioFile, PathBuf)>,
+    silent_frames: usize,
+}
+
+impl Recorder {
+    fn run(&mut self, mut queue: SampleQueue) -> io::Result<()> {
+        loop {
+            while let Some(frame) = queue.pop() {
+                self.write_frame(&frame)?;
+            }
+
+            if queue.is_finished() {
+                return self.finish_file();
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn write_frame(&mut self, frame: &BufferSample) -> io::Result<()> {
+        let channels = self.format.channels as usize;
+        if frame[..channels].iter().all(|&sample| sample == 0.0) {
+            self.silent_frames += 1;
+        } else {
+            self.silent_frames = 0;
+        }
+
+        if let Some(split_after_frames) = self.split_after_frames {
+            if self.silent_frames > split_after_frames {
+                // Drop the rest of the silence instead of starting a file
+                // with it.
+                return self.finish_file();
+            }
+            if self.file.is_none() && self.silent_frames == 0 {
+                self.open_file()?;
+            }
+        }
+
+        if let Some((file, _)) = &mut self.file {
+            let mut frame = *frame;
+            for sample in frame.iter_mut() {
+                *sample *= self.gain;
+            }
+            file.write_frame(&frame)?;
+        }
+
+        Ok(())
+    }
+
+    fn open_file(&mut self) -> io::Result<()> {
+        let name = file_name(&self.template, self.addr, SystemTime::now());
+        // Streams with more channels than FLAC can hold are recorded as WAV.
+        #[cfg(feature = "flac")]
+        let name = match is_flac(&name) && self.format.channels > FLAC_MAX_CHANNELS {
+            true => {
+                eprintln!(
+                    "FLAC holds at most {} channels, recording {} channels as WAV",
+                    FLAC_MAX_CHANNELS, self.format.channels
+                );
+                format!("{}.wav", &name[..name.len() - ".flac".len()])
+            }
+            false => name,
+        };
+        let path = unused_path(&name);
+        eprintln!("Recording to {}", path.display());
+
+        self.file = Some((AudioFile::create(&path, &self.format)?, path));
+        Ok(())
+    }
+
+    fn finish_file(&mut self) -> io::Result<()> {
+        if let Some((file, path)) = self.file.take() {
+            file.finish()?;
+            eprintln!("Finished recording {}", path.display());
+        }
+        Ok(())
+    }
+}
+
+fn is_flac(path: &str) -> bool {
+    path.to_lowercase().ends_with(".flac")
+}
+
+/// Fills in the placeholders of a recording path template.
+fn file_name(template: &str, addr: IpAddr, time: SystemTime) -> String {
+    // IPv6 addresses contain colons, which not every file system allows.
+    let addr = addr.to_string().replace(':', "-");
+    template
+        .replace("{time}", &format_utc(time))
+        .replace("{addr}", &addr)
+}
+
+/// Appends a number to the file name if the file already exists, e.g. when
+/// two files are started within a second.
+fn unused_path(name: &str) -> PathBuf {
+    let path = PathBuf::from(name);
+    if !path.exists() {
+        return path;
+    }
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    (2..)
+        .map(|n| path.with_file_name(format!("{}-{}{}", stem, n, extension)))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+/// Formats a time as YYYYMMDD-HHMMSS in UTC.
+fn format_utc(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = (seconds / 86400) as i64;
+    let seconds_of_day = seconds % 86400;
+
+    // Days to civil date, from Howard Hinnant's date algorithms.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scream::MAX_CHANNELS;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn stop_finishes_files() {
+        let path =
+            std::env::temp_dir().join(format!("screamreader-recording-{}.wav", std::process::id()));
+        let config = ReceiverConfig {
+            record_path: Some(path.to_string_lossy().into_owned()),
+            record_split_silence: None,
+            ..ReceiverConfig::default()
+        };
+        let format = ScreamFormat {
+            sample_rate: 48000,
+            sample_bits: 16,
+            channels: 2,
+            channel_mask: 0x3,
+        };
+
+        let mut sink = RecordingSink::new(&config).unwrap();
+        let mut buffer = sink
+            .start_source(IpAddr::V4(Ipv4Addr::LOCALHOST), &format, 1.0)
+            .unwrap();
+        for _ in 0..1000 {
+            buffer.push([0.5; MAX_CHANNELS]).unwrap();
+        }
+        drop(buffer);
+        sink.stop();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + 1000 * 4);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(bytes[40..44], 4000u32.to_le_bytes());
+        assert_eq!(bytes[4..8], (36 + 4000u32).to_le_bytes());
+        sink.poll_error().unwrap();
+    }
+}
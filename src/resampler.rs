@@ -0,0 +1,275 @@
+use crate::output_stream::BufferSample;
+use crate::scream::MAX_CHANNELS;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of precomputed filter phases between two input samples. Phases in
+/// between are linearly interpolated.
+const FILTER_PHASES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResampleQuality {
+    /// 8 tap windowed sinc, lowest CPU usage.
+    Fast,
+    /// 32 tap windowed sinc.
+    Medium,
+    /// 128 tap windowed sinc, best stop band attenuation.
+    High,
+}
+
+impl FromStr for ResampleQuality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fast" => Ok(ResampleQuality::Fast),
+            "medium" => Ok(ResampleQuality::Medium),
+            "high" => Ok(ResampleQuality::High),
+            _ => Err(format!("unknown resample quality '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for ResampleQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ResampleQuality::Fast => "fast",
+            ResampleQuality::Medium => "medium",
+            ResampleQuality::High => "high",
+        };
+        f.write_str(name)
+    }
+}
+
+impl ResampleQuality {
+    fn half_taps(self) -> usize {
+        match self {
+            ResampleQuality::Fast => 4,
+            ResampleQuality::Medium => 16,
+            ResampleQuality::High => 64,
+        }
+    }
+}
+
+/// Polyphase windowed sinc resampler working on whole frames. Input frames
+/// are pulled on demand, so it can sit directly between the ring buffer and
+/// the output callback.
+pub struct Resampler {
+    /// Input frames consumed per output frame, as given by the sample rates.
+    nominal_ratio: f64,
+    /// Nominal ratio with the drift correction applied.
+    ratio: f64,
+    /// Fractional position of the next output frame, relative to the center
+    /// of the history.
+    position: f64,
+    channels: usize,
+    half_taps: usize,
+    history: VecDeque<BufferSample>,
+    /// `FILTER_PHASES + 1` rows of `2 * half_taps` coefficients.
+    filter: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(
+        input_rate: u32,
+        output_rate: u32,
+        channels: usize,
+        quality: ResampleQuality,
+    ) -> Resampler {
+        let ratio = input_rate as f64 / output_rate as f64;
+        let half_taps = quality.half_taps();
+        let taps = half_taps * 2;
+
+        // When downsampling, the cutoff has to move below the output
+        // Nyquist frequency to avoid aliasing.
+        let cutoff = 0.97 * f64::min(1.0, 1.0 / ratio);
+
+        let mut filter = Vec::with_capacity((FILTER_PHASES + 1) * taps);
+        for phase in 0..=FILTER_PHASES {
+            let fraction = phase as f64 / FILTER_PHASES as f64;
+            for tap in 0..taps {
+                let x = tap as f64 - (half_taps as f64 - 1.0) - fraction;
+                filter.push((cutoff * sinc(cutoff * x) * blackman(x, half_taps as f64)) as f32);
+            }
+        }
+
+        Resampler {
+            nominal_ratio: ratio,
+            ratio,
+            position: 0.0,
+            channels: channels.min(MAX_CHANNELS),
+            half_taps,
+            history: VecDeque::from(vec![[0.0; MAX_CHANNELS]; taps]),
+            filter,
+        }
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    /// Speeds up (positive) or slows down (negative) consumption of input
+    /// frames by the given relative amount to compensate for clock drift.
+    pub fn set_drift_correction(&mut self, correction: f64) {
+        self.ratio = self.nominal_ratio * (1.0 + correction);
+    }
+
+    /// Produces one output frame, calling `pull` for every input frame that
+    /// is needed to get there.
+    pub fn next_frame(&mut self, mut pull: impl FnMut() -> BufferSample) -> BufferSample {
+        while self.position >= 1.0 {
+            self.history.pop_front();
+            self.history.push_back(pull());
+            self.position -= 1.0;
+        }
+
+        let phase = self.position * FILTER_PHASES as f64;
+        let phase_index = (phase as usize).min(FILTER_PHASES - 1);
+        let phase_fraction = (phase - phase_index as f64) as f32;
+
+        let taps = self.half_taps * 2;
+        let lower = &self.filter[phase_index * taps..(phase_index + 1) * taps];
+        let upper = &self.filter[(phase_index + 1) * taps..(phase_index + 2) * taps];
+
+        let mut output = [0.0f32; MAX_CHANNELS];
+        for (tap, frame) in self.history.iter().enumerate() {
+            let coefficient = lower[tap] + (upper[tap] - lower[tap]) * phase_fraction;
+            for channel in 0..self.channels {
+                output[channel] += frame[channel] * coefficient;
+            }
+        }
+
+        self.position += self.ratio;
+        output
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+fn blackman(x: f64, half_width: f64) -> f64 {
+    if x.abs() >= half_width {
+        return 0.0;
+    }
+
+    let n = PI * x / half_width;
+    0.42 + 0.5 * n.cos() + 0.08 * (2.0 * n).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Produces `frames` output frames from a constant input and returns how
+    /// many input frames were pulled.
+    fn pulled_for(resampler: &mut Resampler, frames: usize) -> usize {
+        let mut pulled = 0;
+        for _ in 0..frames {
+            resampler.next_frame(|| {
+                pulled += 1;
+                [0.5; MAX_CHANNELS]
+            });
+        }
+        pulled
+    }
+
+    #[test]
+    fn drift_correction_raises_ratio() {
+        let mut resampler = Resampler::new(48000, 48000, 2, ResampleQuality::Medium);
+        resampler.set_drift_correction(0.01);
+        assert!((resampler.ratio() - 1.01).abs() < 1e-12);
+        assert!(pulled_for(&mut resampler, 10000).abs_diff(10100) <= 2);
+
+        resampler.set_drift_correction(-0.01);
+        assert!((resampler.ratio() - 0.99).abs() < 1e-12);
+        assert!(pulled_for(&mut resampler, 10000).abs_diff(9900) <= 2);
+    }
+
+    #[test]
+    fn pulls_frames_at_the_rate_ratio() {
+        for (input_rate, output_rate) in [(44100, 48000), (48000, 44100), (96000, 48000)] {
+            let mut resampler = Resampler::new(input_rate, output_rate, 2, ResampleQuality::Fast);
+            let expected = 48000 * input_rate as usize / output_rate as usize;
+            let pulled = pulled_for(&mut resampler, 48000);
+            // Input frames are pulled as the next output frame needs them, so
+            // the last output frame's share is still missing.
+            assert!(
+                pulled <= expected && expected - pulled <= 2,
+                "{} -> {}: {}",
+                input_rate,
+                output_rate,
+                pulled
+            );
+        }
+    }
+
+    #[test]
+    fn passes_dc_at_unity_gain() {
+        for quality in [
+            ResampleQuality::Fast,
+            ResampleQuality::Medium,
+            ResampleQuality::High,
+        ] {
+            for (input_rate, output_rate) in [(48000, 48000), (44100, 48000), (48000, 44100)] {
+                let mut resampler = Resampler::new(input_rate, output_rate, 2, quality);
+                // Fill the history before looking at the output.
+                pulled_for(&mut resampler, 200);
+
+                for _ in 0..1000 {
+                    let frame = resampler.next_frame(|| [0.5; MAX_CHANNELS]);
+                    for &sample in &frame[..2] {
+                        assert!((sample - 0.5).abs() < 0.005, "{} with {}", sample, quality);
+                    }
+                    // Channels beyond the stream's stay silent.
+                    assert_eq!(frame[2], 0.0);
+                }
+            }
+        }
+    }
+
+    /// RMS of a sine of amplitude 0.5 after resampling.
+    fn sine_rms(input_rate: u32, output_rate: u32, frequency: f64) -> f64 {
+        let mut resampler = Resampler::new(input_rate, output_rate, 1, ResampleQuality::Medium);
+        let mut index = 0;
+        let mut next_frame = || {
+            let mut frame = [0.0; MAX_CHANNELS];
+            frame[0] =
+                (0.5 * (2.0 * PI * frequency * index as f64 / input_rate as f64).sin()) as f32;
+            index += 1;
+            frame
+        };
+
+        for _ in 0..200 {
+            resampler.next_frame(&mut next_frame);
+        }
+        let frames = output_rate as usize;
+        let energy: f64 = (0..frames)
+            .map(|_| resampler.next_frame(&mut next_frame)[0] as f64)
+            .map(|sample| sample * sample)
+            .sum();
+        (energy / frames as f64).sqrt()
+    }
+
+    #[test]
+    fn passes_sine_at_unity_gain() {
+        let expected = 0.5 / 2.0f64.sqrt();
+        for (input_rate, output_rate) in [(44100, 48000), (48000, 44100), (96000, 48000)] {
+            let rms = sine_rms(input_rate, output_rate, 1000.0);
+            assert!((rms / expected - 1.0).abs() < 0.01, "{}", rms);
+        }
+    }
+
+    #[test]
+    fn attenuates_above_output_nyquist() {
+        // 30 kHz doesn't fit in 48 kHz and would alias to 18 kHz.
+        let rms = sine_rms(96000, 48000, 30000.0);
+        assert!(rms < 0.01, "{}", rms);
+    }
+}
@@ -0,0 +1,454 @@
+use crate::config::ReceiverConfig;
+use crate::error::{Error, Result};
+use crate::scream::{default_channel_mask, ScreamFormat};
+use byteorder::{BigEndian, ByteOrder};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Size of the RTP header without CSRCs and extension.
+pub const RTP_HEADER_SIZE: usize = 12;
+
+/// The parts of an RTP header the receiver uses.
+#[derive(Debug, Clone, Copy)]
+pub struct RtpHeader {
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    /// Where the payload starts, after the CSRCs and the header extension.
+    pub payload_start: usize,
+    /// Where the payload ends, before any padding.
+    pub payload_end: usize,
+}
+
+impl RtpHeader {
+    /// Parses the header of an RTP packet, or returns `None` if it isn't a
+    /// valid RTP version 2 packet.
+    pub fn parse(packet: &[u8]) -> Option<RtpHeader> {
+        if packet.len() < RTP_HEADER_SIZE || packet[0] >> 6 != 2 {
+            return None;
+        }
+
+        let has_padding = packet[0] & 0x20 != 0;
+        let has_extension = packet[0] & 0x10 != 0;
+        let csrc_count = (packet[0] & 0x0f) as usize;
+
+        let mut payload_start = RTP_HEADER_SIZE + 4 * csrc_count;
+        if has_extension {
+            let extension = packet.get(payload_start..payload_start + 4)?;
+            payload_start += 4 + 4 * BigEndian::read_u16(&extension[2..]) as usize;
+        }
+
+        let mut payload_end = packet.len();
+        if has_padding {
+            payload_end = payload_end.checked_sub(*packet.last()? as usize)?;
+        }
+
+        if payload_start > payload_end {
+            return None;
+        }
+
+        Some(RtpHeader {
+            payload_type: packet[1] & 0x7f,
+            sequence: BigEndian::read_u16(&packet[2..]),
+            timestamp: BigEndian::read_u32(&packet[4..]),
+            payload_start,
+            payload_end,
+        })
+    }
+}
+
+/// Uncompressed RTP payload formats (RFC 3551, RFC 3190). Samples are
+/// big-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtpEncoding {
+    L16,
+    L24,
+}
+
+impl RtpEncoding {
+    pub fn sample_bits(self) -> u8 {
+        match self {
+            RtpEncoding::L16 => 16,
+            RtpEncoding::L24 => 24,
+        }
+    }
+}
+
+/// Format of an RTP audio stream, as given by an SDP `rtpmap` attribute,
+/// e.g. "L24/48000/2".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpFormat {
+    pub encoding: RtpEncoding,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Only packets with this payload type are played. `None` plays all.
+    pub payload_type: Option<u8>,
+}
+
+impl RtpFormat {
+    /// The same format as a Scream stream, with the channels in the default
+    /// order for their count.
+    pub fn scream_format(&self) -> ScreamFormat {
+        ScreamFormat {
+            sample_rate: self.sample_rate,
+            sample_bits: self.encoding.sample_bits(),
+            channels: self.channels,
+            channel_mask: default_channel_mask(self.channels),
+        }
+    }
+
+    /// Format of the static payload types for uncompressed audio.
+    fn for_static_payload_type(payload_type: u8) -> Option<RtpFormat> {
+        let channels = match payload_type {
+            10 => 2,
+            11 => 1,
+            _ => return None,
+        };
+
+        Some(RtpFormat {
+            encoding: RtpEncoding::L16,
+            sample_rate: 44100,
+            channels,
+            payload_type: Some(payload_type),
+        })
+    }
+}
+
+impl FromStr for RtpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+
+        let encoding = match parts.next().map(|e| e.to_ascii_uppercase()).as_deref() {
+            Some("L16") => RtpEncoding::L16,
+            Some("L24") => RtpEncoding::L24,
+            _ => return Err(format!("unsupported RTP encoding in '{}'", s)),
+        };
+        let sample_rate = match parts.next().map(str::parse) {
+            Some(Ok(rate)) if rate > 0 => rate,
+            _ => return Err(format!("missing or invalid sample rate in '{}'", s)),
+        };
+        // The channel count is optional and defaults to mono.
+        let channels = match parts.next().map(str::parse) {
+            None => 1,
+            Some(Ok(channels)) if channels > 0 => channels,
+            Some(_) => return Err(format!("invalid channel count in '{}'", s)),
+        };
+
+        Ok(RtpFormat {
+            encoding,
+            sample_rate,
+            channels,
+            payload_type: None,
+        })
+    }
+}
+
+impl fmt::Display for RtpFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoding = match self.encoding {
+            RtpEncoding::L16 => "L16",
+            RtpEncoding::L24 => "L24",
+        };
+        write!(f, "{}/{}/{}", encoding, self.sample_rate, self.channels)
+    }
+}
+
+/// The parts of an SDP session description needed to receive its first
+/// audio stream.
+#[derive(Debug, Clone)]
+pub struct SdpSession {
+    pub format: RtpFormat,
+    pub port: u16,
+    /// Connection address, a multicast group or the receiver's own address.
+    pub address: Option<IpAddr>,
+}
+
+impl SdpSession {
+    pub fn parse(sdp: &str) -> Result<SdpSession> {
+        let mut session_address = None;
+        let mut media: Option<(u16, Vec<u8>)> = None;
+        let mut media_address = None;
+        let mut rtpmaps = Vec::new();
+
+        for line in sdp.lines().map(str::trim) {
+            if let Some(connection) = line.strip_prefix("c=") {
+                // c=IN IP4 239.1.2.3/32
+                let address = connection
+                    .split_whitespace()
+                    .nth(2)
+                    .and_then(|address| address.split('/').next())
+                    .and_then(|address| address.parse().ok());
+                match media {
+                    Some(_) => media_address = media_address.or(address),
+                    None => session_address = address,
+                }
+            } else if let Some(description) = line.strip_prefix("m=") {
+                // m=audio 5004 RTP/AVP 96. Only the first audio stream is used.
+                if media.is_some() {
+                    break;
+                }
+                let fields: Vec<&str> = description.split_whitespace().collect();
+                if fields.len() < 4 || fields[0] != "audio" {
+                    continue;
+                }
+                let port = fields[1]
+                    .split('/')
+                    .next()
+                    .and_then(|port| port.parse().ok())
+                    .ok_or_else(|| Error::Sdp(format!("invalid port in '{}'", line)))?;
+                let payload_types = fields[3..]
+                    .iter()
+                    .filter_map(|pt| pt.parse().ok())
+                    .collect();
+                media = Some((port, payload_types));
+            } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+                // a=rtpmap:96 L24/48000/2
+                if let Some((payload_type, format)) = rtpmap.split_once(' ') {
+                    if let Ok(payload_type) = payload_type.parse::<u8>() {
+                        rtpmaps.push((payload_type, format.trim().to_string()));
+                    }
+                }
+            }
+        }
+
+        let (port, payload_types) =
+            media.ok_or_else(|| Error::Sdp("no audio stream described".to_string()))?;
+
+        let format = payload_types
+            .iter()
+            .find_map(|&payload_type| {
+                let rtpmap = rtpmaps.iter().find(|(pt, _)| *pt == payload_type);
+                match rtpmap {
+                    Some((_, format)) => format.parse::<RtpFormat>().ok().map(|format| RtpFormat {
+                        payload_type: Some(payload_type),
+                        ..format
+                    }),
+                    None => RtpFormat::for_static_payload_type(payload_type),
+                }
+            })
+            .ok_or_else(|| Error::Sdp("no L16 or L24 payload type".to_string()))?;
+
+        Ok(SdpSession {
+            format,
+            port,
+            address: media_address.or(session_address),
+        })
+    }
+
+    /// Sets up the config to receive this session: the payload format, the
+    /// port, and the multicast group, if the session is sent to one.
+    pub fn configure(&self, config: &mut ReceiverConfig) {
+        config.rtp_format = Some(self.format);
+        config.port = self.port;
+
+        match self.address {
+            Some(IpAddr::V4(group)) if group.is_multicast() => {
+                config.multicast_group = group;
+                config.unicast = false;
+            }
+            _ => config.unicast = true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// An RTP packet with payload type 96, sequence 0x1234 and timestamp
+    /// 0xdeadbeef.
+    fn packet(csrcs: usize, extension_words: Option<u16>, payload: &[u8], padding: u8) -> Vec<u8> {
+        let mut packet = vec![0x80 | csrcs as u8, 96, 0x12, 0x34, 0xde, 0xad, 0xbe, 0xef];
+        packet.extend_from_slice(&[0, 0, 0, 1]);
+        packet.resize(packet.len() + 4 * csrcs, 0xcc);
+        if let Some(words) = extension_words {
+            packet[0] |= 0x10;
+            packet.extend_from_slice(&[0xbe, 0xde]);
+            packet.extend_from_slice(&words.to_be_bytes());
+            packet.resize(packet.len() + 4 * words as usize, 0xee);
+        }
+        packet.extend_from_slice(payload);
+        if padding > 0 {
+            packet[0] |= 0x20;
+            packet.resize(packet.len() + padding as usize - 1, 0);
+            packet.push(padding);
+        }
+        packet
+    }
+
+    #[test]
+    fn parses_plain_header() {
+        let packet = packet(0, None, &[1, 2, 3, 4], 0);
+        let header = RtpHeader::parse(&packet).unwrap();
+
+        assert_eq!(header.payload_type, 96);
+        assert_eq!(header.sequence, 0x1234);
+        assert_eq!(header.timestamp, 0xdeadbeef);
+        assert_eq!(
+            &packet[header.payload_start..header.payload_end],
+            [1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn skips_csrcs_extension_and_padding() {
+        let packet = packet(2, Some(3), &[1, 2, 3, 4], 4);
+        let header = RtpHeader::parse(&packet).unwrap();
+
+        assert_eq!(header.payload_start, RTP_HEADER_SIZE + 8 + 4 + 12);
+        assert_eq!(
+            &packet[header.payload_start..header.payload_end],
+            [1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn ignores_marker_bit() {
+        let mut packet = packet(0, None, &[1, 2], 0);
+        packet[1] |= 0x80;
+
+        assert_eq!(RtpHeader::parse(&packet).unwrap().payload_type, 96);
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut packet = packet(0, None, &[1, 2], 0);
+        packet[0] = 0x40;
+
+        assert!(RtpHeader::parse(&packet).is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let packet = packet(2, Some(3), &[1, 2, 3, 4], 4);
+
+        // Every prefix that cuts into the header is invalid. Cutting into
+        // the payload can't always be told from padding, but mustn't panic.
+        for len in 0..packet.len() {
+            let header = RtpHeader::parse(&packet[..len]);
+            if len < RTP_HEADER_SIZE + 8 + 4 + 12 {
+                assert!(header.is_none(), "{} bytes", len);
+            }
+        }
+        assert!(RtpHeader::parse(&packet).is_some());
+    }
+
+    #[test]
+    fn rejects_inconsistent_lengths() {
+        // More CSRCs than the packet holds.
+        let mut csrcs = packet(0, None, &[1, 2, 3, 4], 0);
+        csrcs[0] |= 0x0f;
+        assert!(RtpHeader::parse(&csrcs).is_none());
+
+        // Extension longer than the packet.
+        let mut extension = packet(0, Some(1), &[1, 2, 3, 4], 0);
+        extension[RTP_HEADER_SIZE + 2..RTP_HEADER_SIZE + 4].copy_from_slice(&[0xff, 0xff]);
+        assert!(RtpHeader::parse(&extension).is_none());
+
+        // More padding than there is payload.
+        let mut padding = packet(0, None, &[1, 2, 3, 4], 4);
+        *padding.last_mut().unwrap() = 200;
+        assert!(RtpHeader::parse(&padding).is_none());
+    }
+
+    #[test]
+    fn parses_rtpmap_format() {
+        let format: RtpFormat = "L24/48000/2".parse().unwrap();
+        assert_eq!(format.encoding, RtpEncoding::L24);
+        assert_eq!(format.sample_rate, 48000);
+        assert_eq!(format.channels, 2);
+        assert_eq!(format.to_string(), "L24/48000/2");
+
+        let format: RtpFormat = "l16/44100".parse().unwrap();
+        assert_eq!(format.encoding, RtpEncoding::L16);
+        assert_eq!(format.channels, 1);
+
+        assert!("opus/48000/2".parse::<RtpFormat>().is_err());
+        assert!("L16".parse::<RtpFormat>().is_err());
+        assert!("L16/0".parse::<RtpFormat>().is_err());
+        assert!("L16/48000/0".parse::<RtpFormat>().is_err());
+    }
+
+    #[test]
+    fn uses_static_payload_types() {
+        let session = SdpSession::parse("v=0\nm=audio 5004 RTP/AVP 10\n").unwrap();
+        assert_eq!(
+            session.format,
+            RtpFormat {
+                encoding: RtpEncoding::L16,
+                sample_rate: 44100,
+                channels: 2,
+                payload_type: Some(10),
+            }
+        );
+
+        let session = SdpSession::parse("v=0\nm=audio 5004 RTP/AVP 0 11\n").unwrap();
+        assert_eq!(session.format.channels, 1);
+        assert_eq!(session.format.payload_type, Some(11));
+    }
+
+    #[test]
+    fn picks_first_supported_dynamic_payload_type() {
+        let sdp = "v=0\r\n\
+                   o=- 1 1 IN IP4 10.0.0.1\r\n\
+                   s=Stream\r\n\
+                   c=IN IP4 239.69.1.2/32\r\n\
+                   t=0 0\r\n\
+                   m=audio 5004/1 RTP/AVP 97 98\r\n\
+                   a=rtpmap:97 opus/48000/2\r\n\
+                   a=rtpmap:98 L24/96000/8\r\n\
+                   m=audio 5006 RTP/AVP 99\r\n\
+                   a=rtpmap:99 L16/48000/2\r\n";
+        let session = SdpSession::parse(sdp).unwrap();
+
+        assert_eq!(session.port, 5004);
+        assert_eq!(session.format.to_string(), "L24/96000/8");
+        assert_eq!(session.format.payload_type, Some(98));
+        assert_eq!(session.address, Some(Ipv4Addr::new(239, 69, 1, 2).into()));
+    }
+
+    #[test]
+    fn media_connection_overrides_session_connection() {
+        let sdp = "v=0\n\
+                   c=IN IP4 239.69.1.2\n\
+                   m=audio 5004 RTP/AVP 96\n\
+                   c=IN IP4 192.168.1.20\n\
+                   a=rtpmap:96 L16/48000/2\n";
+        let session = SdpSession::parse(sdp).unwrap();
+        assert_eq!(session.address, Some(Ipv4Addr::new(192, 168, 1, 20).into()));
+
+        let mut config = ReceiverConfig::default();
+        session.configure(&mut config);
+        assert!(config.unicast);
+        assert_eq!(config.port, 5004);
+        assert_eq!(config.rtp_format, Some(session.format));
+    }
+
+    #[test]
+    fn session_connection_applies_to_media() {
+        let sdp = "v=0\n\
+                   c=IN IP4 239.69.1.2/32\n\
+                   m=audio 5004 RTP/AVP 96\n\
+                   a=rtpmap:96 L16/48000/2\n";
+        let session = SdpSession::parse(sdp).unwrap();
+
+        let mut config = ReceiverConfig {
+            unicast: true,
+            ..ReceiverConfig::default()
+        };
+        session.configure(&mut config);
+        assert!(!config.unicast);
+        assert_eq!(config.multicast_group, Ipv4Addr::new(239, 69, 1, 2));
+    }
+
+    #[test]
+    fn rejects_sessions_without_usable_audio() {
+        assert!(SdpSession::parse("v=0\nm=video 5004 RTP/AVP 96\n").is_err());
+        assert!(SdpSession::parse("v=0\nm=audio 5004 RTP/AVP 96\n").is_err());
+        assert!(SdpSession::parse("v=0\nm=audio x RTP/AVP 10\n").is_err());
+    }
+}
@@ -1,31 +1,327 @@
-pub const SCREAM_PACKET_MAX_SIZE: usize = 1157;
-
-pub type ScreamPacket = [u8; SCREAM_PACKET_MAX_SIZE];
-
-pub type ScreamHeaderArray = [u8; 5];
-
-pub trait ScreamHeader {
-    fn sample_rate(&self) -> u32;
-    fn sample_bits(&self) -> u8;
-    fn channels(&self) -> u16;
-    fn sample_bytes(&self) -> usize {
-        return self.sample_bits() as usize / 8;
-    }
-}
-
-impl ScreamHeader for ScreamHeaderArray {
-    fn sample_rate(&self) -> u32 {
-        let rate_byte = self[0];
-        let multiplier = (rate_byte & 0b01111111) as u32;
-        return match rate_byte & 0b10000000 == 0 {
-            true => 48000 * multiplier,
-            false => 44100 * multiplier,
-        };
-    }
-    fn sample_bits(&self) -> u8 {
-        return self[1];
-    }
-    fn channels(&self) -> u16 {
-        return 2; // TODO
-    }
-}
+use byteorder::{ByteOrder, LittleEndian};
+
+pub const SCREAM_HEADER_SIZE: usize = 5;
+
+pub const SCREAM_PACKET_MAX_SIZE: usize = 1157;
+
+/// Maximum number of channels a stream can have.
+pub const MAX_CHANNELS: usize = 10;
+
+pub type ScreamPacket = [u8; SCREAM_PACKET_MAX_SIZE];
+
+pub type ScreamHeaderArray = [u8; 5];
+
+/// Speaker positions as used in the `dwChannelMask` field of Windows'
+/// WAVEFORMATEXTENSIBLE. Scream only transmits the lower 16 bits of the mask.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SpeakerPosition {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    BackLeft,
+    BackRight,
+    FrontLeftOfCenter,
+    FrontRightOfCenter,
+    BackCenter,
+    SideLeft,
+    SideRight,
+    TopCenter,
+    TopFrontLeft,
+    TopFrontCenter,
+    TopFrontRight,
+    TopBackLeft,
+    /// Channel that has no corresponding bit set in the channel mask.
+    Unassigned,
+}
+
+const SPEAKER_POSITIONS_BY_BIT: [SpeakerPosition; 16] = [
+    SpeakerPosition::FrontLeft,
+    SpeakerPosition::FrontRight,
+    SpeakerPosition::FrontCenter,
+    SpeakerPosition::LowFrequency,
+    SpeakerPosition::BackLeft,
+    SpeakerPosition::BackRight,
+    SpeakerPosition::FrontLeftOfCenter,
+    SpeakerPosition::FrontRightOfCenter,
+    SpeakerPosition::BackCenter,
+    SpeakerPosition::SideLeft,
+    SpeakerPosition::SideRight,
+    SpeakerPosition::TopCenter,
+    SpeakerPosition::TopFrontLeft,
+    SpeakerPosition::TopFrontCenter,
+    SpeakerPosition::TopFrontRight,
+    SpeakerPosition::TopBackLeft,
+];
+
+/// Channel mask Windows uses by default for the given channel count, used
+/// when the sender does not provide one.
+pub fn default_channel_mask(channels: u16) -> u16 {
+    match channels {
+        1 => 0x0004, // mono: FC
+        2 => 0x0003, // stereo: FL FR
+        3 => 0x0007, // FL FR FC
+        4 => 0x0033, // quad: FL FR BL BR
+        5 => 0x0037, // FL FR FC BL BR
+        6 => 0x003F, // 5.1: FL FR FC LFE BL BR
+        7 => 0x013F, // 6.1: FL FR FC LFE BL BR BC
+        8 => 0x063F, // 7.1: FL FR FC LFE BL BR SL SR
+        _ => 0,
+    }
+}
+
+/// Maps each bit set in the channel mask, lowest bit first, to a channel.
+pub fn speaker_positions_from_mask(channels: u16, mask: u16) -> Vec<SpeakerPosition> {
+    let mut positions: Vec<SpeakerPosition> = SPEAKER_POSITIONS_BY_BIT
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| mask & (1 << bit) != 0)
+        .map(|(_, position)| *position)
+        .take(channels as usize)
+        .collect();
+
+    positions.resize(channels as usize, SpeakerPosition::Unassigned);
+    positions
+}
+
+pub trait ScreamHeader {
+    fn sample_rate(&self) -> u32;
+    fn sample_bits(&self) -> u8;
+    fn channels(&self) -> u16;
+    fn channel_mask(&self) -> u16;
+    fn sample_bytes(&self) -> usize {
+        self.sample_bits() as usize / 8
+    }
+    fn speaker_positions(&self) -> Vec<SpeakerPosition> {
+        speaker_positions_from_mask(self.channels(), self.channel_mask())
+    }
+}
+
+impl ScreamHeader for ScreamHeaderArray {
+    fn sample_rate(&self) -> u32 {
+        let rate_byte = self[0];
+        let multiplier = (rate_byte & 0b01111111) as u32;
+        match rate_byte & 0b10000000 == 0 {
+            true => 48000 * multiplier,
+            false => 44100 * multiplier,
+        }
+    }
+    fn sample_bits(&self) -> u8 {
+        self[1]
+    }
+    fn channels(&self) -> u16 {
+        self[2] as u16
+    }
+    fn channel_mask(&self) -> u16 {
+        match LittleEndian::read_u16(&self[3..5]) {
+            0 => default_channel_mask(self.channels()),
+            mask => mask,
+        }
+    }
+}
+
+/// Stream format parsed from a Scream header.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct ScreamFormat {
+    pub sample_rate: u32,
+    pub sample_bits: u8,
+    pub channels: u16,
+    pub channel_mask: u16,
+}
+
+impl ScreamFormat {
+    pub fn from_header(header: &ScreamHeaderArray) -> ScreamFormat {
+        ScreamFormat {
+            sample_rate: header.sample_rate(),
+            sample_bits: header.sample_bits(),
+            channels: header.channels(),
+            channel_mask: header.channel_mask(),
+        }
+    }
+
+    /// Whether samples in this format can be decoded.
+    pub fn is_supported(&self) -> bool {
+        let channels = self.channels as usize;
+        let supported_bits = matches!(self.sample_bits, 16 | 24 | 32);
+
+        supported_bits && channels > 0 && channels <= MAX_CHANNELS && self.sample_rate > 0
+    }
+
+    /// Encodes the format as a Scream header, the inverse of `from_header`.
+    /// Returns `None` if the sample rate isn't a multiple of 44100 or 48000
+    /// Hz, which is all the header can express.
+    pub fn to_header(&self) -> Option<ScreamHeaderArray> {
+        let (base, multiplier) = match self.sample_rate {
+            rate if rate % 48000 == 0 => (0, rate / 48000),
+            rate if rate % 44100 == 0 => (0b10000000, rate / 44100),
+            _ => return None,
+        };
+        if !(1..=0b01111111).contains(&multiplier) {
+            return None;
+        }
+        let channels = u8::try_from(self.channels).ok()?;
+
+        let mut header = [base | multiplier as u8, self.sample_bits, channels, 0, 0];
+        LittleEndian::write_u16(&mut header[3..5], self.channel_mask);
+        Some(header)
+    }
+
+    /// Size of one sample for every channel, in bytes.
+    pub fn frame_bytes(&self) -> usize {
+        self.sample_bytes() * self.channels as usize
+    }
+}
+
+impl ScreamHeader for ScreamFormat {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    fn sample_bits(&self) -> u8 {
+        self.sample_bits
+    }
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+    fn channel_mask(&self) -> u16 {
+        self.channel_mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SpeakerPosition::*;
+
+    #[test]
+    fn parses_header() {
+        // 44.1 kHz times 2, 24 bit, 6 channels, mask 0x060f little-endian.
+        let format = ScreamFormat::from_header(&[0x82, 24, 6, 0x0f, 0x06]);
+
+        assert_eq!(format.sample_rate, 88200);
+        assert_eq!(format.sample_bits, 24);
+        assert_eq!(format.channels, 6);
+        assert_eq!(format.channel_mask, 0x060f);
+        assert_eq!(format.frame_bytes(), 18);
+        assert_eq!(
+            format.speaker_positions(),
+            [
+                FrontLeft,
+                FrontRight,
+                FrontCenter,
+                LowFrequency,
+                SideLeft,
+                SideRight
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_48k_base_rate() {
+        let format = ScreamFormat::from_header(&[0x01, 16, 2, 0x03, 0x00]);
+
+        assert_eq!(format.sample_rate, 48000);
+        assert_eq!(format.speaker_positions(), [FrontLeft, FrontRight]);
+    }
+
+    #[test]
+    fn falls_back_to_default_mask() {
+        let format = ScreamFormat::from_header(&[0x01, 16, 6, 0, 0]);
+        assert_eq!(format.channel_mask, 0x003f);
+
+        let format = ScreamFormat::from_header(&[0x01, 16, 1, 0, 0]);
+        assert_eq!(format.speaker_positions(), [FrontCenter]);
+
+        // No default for ten channels: every channel is unassigned.
+        let format = ScreamFormat::from_header(&[0x01, 16, 10, 0, 0]);
+        assert_eq!(format.channel_mask, 0);
+        assert_eq!(format.speaker_positions(), [Unassigned; 10]);
+    }
+
+    #[test]
+    fn default_masks_have_a_bit_per_channel() {
+        for channels in 1..=8 {
+            assert_eq!(default_channel_mask(channels).count_ones(), channels as u32);
+        }
+        assert_eq!(default_channel_mask(0), 0);
+        assert_eq!(default_channel_mask(9), 0);
+    }
+
+    #[test]
+    fn maps_mask_bits_to_channels() {
+        // More bits than channels: the lowest ones are used.
+        assert_eq!(
+            speaker_positions_from_mask(2, 0x0007),
+            [FrontLeft, FrontRight]
+        );
+
+        // Fewer bits than channels: the rest are unassigned.
+        assert_eq!(
+            speaker_positions_from_mask(3, 0x8001),
+            [FrontLeft, TopBackLeft, Unassigned]
+        );
+
+        assert_eq!(
+            speaker_positions_from_mask(8, 0x063f),
+            [
+                FrontLeft,
+                FrontRight,
+                FrontCenter,
+                LowFrequency,
+                BackLeft,
+                BackRight,
+                SideLeft,
+                SideRight
+            ]
+        );
+    }
+
+    #[test]
+    fn header_round_trips() {
+        for sample_rate in [44100, 48000, 88200, 96000, 176400, 192000] {
+            for (channels, channel_mask) in [(1, 0x4), (2, 0x3), (6, 0x3f), (8, 0x63f), (2, 0x600)]
+            {
+                let format = ScreamFormat {
+                    sample_rate,
+                    sample_bits: 24,
+                    channels,
+                    channel_mask,
+                };
+                let header = format.to_header().unwrap();
+                assert_eq!(ScreamFormat::from_header(&header), format);
+            }
+        }
+    }
+
+    #[test]
+    fn encodes_rate_base_and_little_endian_mask() {
+        let format = ScreamFormat {
+            sample_rate: 88200,
+            sample_bits: 16,
+            channels: 8,
+            channel_mask: 0x063f,
+        };
+        assert_eq!(format.to_header(), Some([0x82, 16, 8, 0x3f, 0x06]));
+
+        let format = ScreamFormat {
+            sample_rate: 48000,
+            ..format
+        };
+        assert_eq!(format.to_header().unwrap()[0], 0x01);
+    }
+
+    #[test]
+    fn rejects_rates_the_header_cannot_carry() {
+        let format = |sample_rate| ScreamFormat {
+            sample_rate,
+            sample_bits: 16,
+            channels: 2,
+            channel_mask: 0x3,
+        };
+
+        assert!(format(32000).to_header().is_none());
+        assert!(format(0).to_header().is_none());
+        assert!(format(48000 * 127).to_header().is_some());
+        // The multiplier has seven bits.
+        assert!(format(48000 * 128).to_header().is_none());
+    }
+}
@@ -0,0 +1,538 @@
+use crate::config::SenderConfig;
+use crate::error::{Error, Result};
+use crate::output_stream::{find_device, select_host};
+use crate::pcm::to_int_sample;
+use crate::scream::{default_channel_mask, ScreamFormat, ScreamHeader, SCREAM_HEADER_SIZE};
+use crate::socket::open_sender_socket;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::net::{SocketAddrV4, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size of the audio in a packet, as sent by the Scream driver.
+pub const SCREAM_PAYLOAD_SIZE: usize = 1152;
+
+/// How far sending may fall behind schedule, e.g. after the input stalled,
+/// before the schedule is moved. Packets that are behind go out at once.
+const MAX_BEHIND: Duration = Duration::from_millis(50);
+
+/// How long to wait for captured audio before checking the stream for
+/// errors.
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Sample rate of PCM input, if none is configured.
+const DEFAULT_PCM_SAMPLE_RATE: u32 = 48000;
+
+/// Channel count of PCM input, if none is configured.
+const DEFAULT_PCM_CHANNELS: u16 = 2;
+
+/// Cuts audio into Scream packets and sends them, one packet's worth of
+/// audio apart.
+pub struct ScreamSender {
+    socket: UdpSocket,
+    destination: SocketAddrV4,
+    format: ScreamFormat,
+    /// Header and the samples of the packet being filled.
+    packet: Vec<u8>,
+    /// Whole frames that fit in `SCREAM_PAYLOAD_SIZE` bytes, in bytes.
+    payload_size: usize,
+    packet_duration: Duration,
+    next_send: Option<Instant>,
+}
+
+impl ScreamSender {
+    pub fn new(config: &SenderConfig, format: ScreamFormat) -> Result<ScreamSender> {
+        let header = format
+            .to_header()
+            .filter(|_| format.is_supported())
+            .ok_or_else(|| {
+                Error::UnsupportedFormat(format!(
+                    "cannot send {} Hz, {} bit, {} channels",
+                    format.sample_rate, format.sample_bits, format.channels
+                ))
+            })?;
+
+        let payload_size = SCREAM_PAYLOAD_SIZE - SCREAM_PAYLOAD_SIZE % format.frame_bytes();
+        let packet_frames = payload_size / format.frame_bytes();
+        let mut packet = Vec::with_capacity(SCREAM_HEADER_SIZE + payload_size);
+        packet.extend_from_slice(&header);
+
+        let target = config.unicast_target.unwrap_or(config.multicast_group);
+
+        Ok(ScreamSender {
+            socket: open_sender_socket(config)?,
+            destination: SocketAddrV4::new(target, config.port),
+            format,
+            packet,
+            payload_size,
+            packet_duration: Duration::from_secs_f64(
+                packet_frames as f64 / format.sample_rate as f64,
+            ),
+            next_send: None,
+        })
+    }
+
+    pub fn format(&self) -> &ScreamFormat {
+        &self.format
+    }
+
+    pub fn destination(&self) -> SocketAddrV4 {
+        self.destination
+    }
+
+    /// Sends interleaved samples in the range -1..1 at real-time speed,
+    /// waiting for each packet's turn, as timed by the system clock.
+    pub fn send(&mut self, samples: &[f32]) -> Result<()> {
+        self.queue(samples, None)
+    }
+
+    /// Sends samples that a device captured at real-time speed and that
+    /// became available at `available_at`. Their packets are spread over the
+    /// time the samples take to play, so that the device's clock sets the
+    /// pace rather than the system's.
+    pub fn send_captured(&mut self, samples: &[f32], available_at: Instant) -> Result<()> {
+        self.queue(samples, Some(available_at))
+    }
+
+    /// Sends what is left of the last packet, if anything.
+    pub fn flush(&mut self) -> Result<()> {
+        let partial_frame = (self.packet.len() - SCREAM_HEADER_SIZE) % self.format.frame_bytes();
+        self.packet.truncate(self.packet.len() - partial_frame);
+        if self.packet.len() > SCREAM_HEADER_SIZE {
+            let due = self.next_due();
+            self.send_packet(due)?;
+        }
+        Ok(())
+    }
+
+    fn queue(&mut self, samples: &[f32], available_at: Option<Instant>) -> Result<()> {
+        let sample_bytes = self.format.sample_bytes();
+        let channels = self.format.channels as usize;
+
+        for (index, &sample) in samples.iter().enumerate() {
+            let bytes = to_int_sample(sample, self.format.sample_bits).to_le_bytes();
+            self.packet.extend_from_slice(&bytes[..sample_bytes]);
+
+            if self.packet.len() < SCREAM_HEADER_SIZE + self.payload_size {
+                continue;
+            }
+
+            let due = match available_at {
+                Some(available_at) => {
+                    let frames = (index + 1) / channels;
+                    available_at
+                        + Duration::from_secs_f64(frames as f64 / self.format.sample_rate as f64)
+                }
+                None => self.next_due(),
+            };
+            self.send_packet(due)?;
+        }
+
+        Ok(())
+    }
+
+    /// When the next packet is due by the system clock: a packet after the
+    /// previous one, but not so far behind that a burst would follow.
+    fn next_due(&self) -> Instant {
+        let now = Instant::now();
+        let earliest = now.checked_sub(MAX_BEHIND).unwrap_or(now);
+        self.next_send
+            .map_or(now, |next_send| next_send.max(earliest))
+    }
+
+    fn send_packet(&mut self, due: Instant) -> Result<()> {
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+
+        self.socket.send_to(&self.packet, self.destination)?;
+        self.packet.truncate(SCREAM_HEADER_SIZE);
+        self.next_send = Some(due + self.packet_duration);
+        Ok(())
+    }
+}
+
+/// Sends the configured input, a PCM file or stream if `pcm_input` is set
+/// and an audio input device otherwise, until it ends or an error occurs.
+pub fn run(config: &SenderConfig) -> Result<()> {
+    match &config.pcm_input {
+        Some(path) => send_pcm_input(config, path),
+        None => send_capture(config),
+    }
+}
+
+/// Reads raw PCM and sends it at real-time speed until the input ends.
+fn send_pcm_input(config: &SenderConfig, path: &str) -> Result<()> {
+    let channels = config.channels.unwrap_or(DEFAULT_PCM_CHANNELS);
+    let format = ScreamFormat {
+        sample_rate: config.sample_rate.unwrap_or(DEFAULT_PCM_SAMPLE_RATE),
+        sample_bits: config.sample_bits,
+        channels,
+        channel_mask: default_channel_mask(channels),
+    };
+    let mut sender = ScreamSender::new(config, format)?;
+
+    let mut input: Box<dyn Read> = match path {
+        "-" => Box::new(io::stdin()),
+        path => Box::new(File::open(path).map_err(Error::PcmInput)?),
+    };
+
+    eprintln!(
+        "Sending {} ({}, {} Hz, {} channels) to {} as {} bit",
+        path,
+        config.pcm_encoding,
+        format.sample_rate,
+        format.channels,
+        sender.destination(),
+        format.sample_bits
+    );
+
+    let encoding = config.pcm_encoding;
+    let sample_bytes = encoding.sample_bytes();
+    let mut buf = vec![0u8; SCREAM_PAYLOAD_SIZE * 4];
+    let mut filled = 0;
+    let mut samples = Vec::new();
+
+    loop {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::PcmInput(e)),
+        }
+
+        let whole = filled - filled % sample_bytes;
+        samples.clear();
+        samples.extend(
+            buf[..whole]
+                .chunks_exact(sample_bytes)
+                .map(|s| encoding.decode(s)),
+        );
+        sender.send(&samples)?;
+
+        buf.copy_within(whole..filled, 0);
+        filled -= whole;
+    }
+
+    sender.flush()?;
+    eprintln!("End of PCM input");
+    Ok(())
+}
+
+/// Captures from an input device and sends it until an error occurs.
+fn send_capture(config: &SenderConfig) -> Result<()> {
+    let host = select_host(config.host.as_deref())?;
+    let device = select_input_device(&host, config.input_device.as_deref())?;
+    let supported_config = select_input_config(&device, config)?;
+    let stream_config = supported_config.config();
+
+    let format = ScreamFormat {
+        sample_rate: stream_config.sample_rate.0,
+        sample_bits: config.sample_bits,
+        channels: stream_config.channels,
+        channel_mask: default_channel_mask(stream_config.channels),
+    };
+    let mut sender = ScreamSender::new(config, format)?;
+
+    let (samples_tx, samples_rx) = mpsc::channel();
+    let (errors_tx, errors_rx) = mpsc::channel();
+
+    let stream = match supported_config.sample_format() {
+        cpal::SampleFormat::F32 => {
+            build_input_stream::<f32>(&device, &stream_config, samples_tx, errors_tx)
+        }
+        cpal::SampleFormat::I16 => {
+            build_input_stream::<i16>(&device, &stream_config, samples_tx, errors_tx)
+        }
+        cpal::SampleFormat::U16 => {
+            build_input_stream::<u16>(&device, &stream_config, samples_tx, errors_tx)
+        }
+    }
+    .map_err(|e| Error::Capture(e.to_string()))?;
+    stream.play().map_err(|e| Error::Capture(e.to_string()))?;
+
+    eprintln!(
+        "Capturing from {}: {} Hz, {:?}, sending to {} as {} bit",
+        device.name().unwrap_or_default(),
+        format.sample_rate,
+        format.speaker_positions(),
+        sender.destination(),
+        format.sample_bits
+    );
+
+    loop {
+        if let Ok(e) = errors_rx.try_recv() {
+            return Err(Error::Capture(e.to_string()));
+        }
+
+        match samples_rx.recv_timeout(CAPTURE_TIMEOUT) {
+            Ok((samples, available_at)) => sender.send_captured(&samples, available_at)?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Error::Capture("input stream stopped".to_string()))
+            }
+        }
+    }
+}
+
+/// Finds an input device the way `select_cpal_device` finds output devices.
+fn select_input_device(host: &cpal::Host, name: Option<&str>) -> Result<cpal::Device> {
+    let device = match name {
+        Some(n) => find_device(host.input_devices()?.collect(), n)?,
+        None => host.default_input_device(),
+    };
+
+    device.ok_or_else(|| Error::DeviceNotFound(name.unwrap_or("default").to_string()))
+}
+
+fn select_input_config(
+    device: &cpal::Device,
+    config: &SenderConfig,
+) -> Result<cpal::SupportedStreamConfig> {
+    let default = device.default_input_config()?;
+    let supported: Vec<_> = device.supported_input_configs()?.collect();
+    choose_input_config(default, &supported, config)
+}
+
+/// Picks the configured rate and channel count, or the device's defaults.
+/// If the default rate can't be sent, 48000 or 44100 Hz is tried instead.
+fn choose_input_config(
+    default: cpal::SupportedStreamConfig,
+    supported: &[cpal::SupportedStreamConfigRange],
+    config: &SenderConfig,
+) -> Result<cpal::SupportedStreamConfig> {
+    let channels = config.channels.unwrap_or_else(|| default.channels());
+    let sample_rates = match config.sample_rate {
+        Some(sample_rate) => vec![sample_rate],
+        None => vec![default.sample_rate().0, 48000, 44100],
+    };
+
+    for sample_rate in sample_rates {
+        let format = ScreamFormat {
+            sample_rate,
+            sample_bits: config.sample_bits,
+            channels,
+            channel_mask: 0,
+        };
+        if format.to_header().is_none() {
+            continue;
+        }
+
+        if default.channels() == channels && default.sample_rate().0 == sample_rate {
+            return Ok(default);
+        }
+
+        let supported = supported.iter().find(|range| {
+            range.channels() == channels
+                && range.min_sample_rate().0 <= sample_rate
+                && sample_rate <= range.max_sample_rate().0
+        });
+        if let Some(range) = supported {
+            return Ok(range
+                .clone()
+                .with_sample_rate(cpal::SampleRate(sample_rate)));
+        }
+    }
+
+    Err(Error::UnsupportedFormat(format!(
+        "the input device can't capture {} channels at a rate Scream can carry",
+        channels
+    )))
+}
+
+fn build_input_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    samples: mpsc::Sender<(Vec<f32>, Instant)>,
+    errors: mpsc::Sender<cpal::StreamError>,
+) -> std::result::Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: cpal::Sample,
+{
+    device.build_input_stream(
+        config,
+        move |input: &[T], _: &cpal::InputCallbackInfo| {
+            let available_at = Instant::now();
+            let input = input.iter().map(cpal::Sample::to_f32).collect();
+            // The sender may already be gone, in which case nobody cares.
+            let _ = samples.send((input, available_at));
+        },
+        move |err| {
+            eprintln!("Input stream error: {}", err);
+            let _ = errors.send(err);
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::{SampleFormat, SampleRate, SupportedBufferSize};
+    use std::net::Ipv4Addr;
+
+    /// Sends the samples to a local socket and returns the packets.
+    fn send_to_local(format: ScreamFormat, samples: &[f32]) -> Vec<Vec<u8>> {
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let config = SenderConfig {
+            unicast_target: Some(Ipv4Addr::LOCALHOST),
+            port: receiver.local_addr().unwrap().port(),
+            ..SenderConfig::default()
+        };
+
+        let mut sender = ScreamSender::new(&config, format).unwrap();
+        sender.send(samples).unwrap();
+        sender.flush().unwrap();
+
+        let mut packets = Vec::new();
+        let mut buf = [0; 2048];
+        while let Ok(size) = receiver.recv(&mut buf) {
+            packets.push(buf[..size].to_vec());
+        }
+        packets
+    }
+
+    #[test]
+    fn fills_packets_with_whole_frames() {
+        let format = ScreamFormat {
+            sample_rate: 48000,
+            sample_bits: 16,
+            channels: 2,
+            channel_mask: 0x3,
+        };
+        // Two and a half packets of 288 frames.
+        let samples: Vec<f32> = (0..720).flat_map(|_| [0.5, -0.5]).collect();
+        let packets = send_to_local(format, &samples);
+
+        let sizes: Vec<usize> = packets.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [1157, 1157, SCREAM_HEADER_SIZE + 144 * 4]);
+        for packet in &packets {
+            assert_eq!(packet[..SCREAM_HEADER_SIZE], format.to_header().unwrap());
+            // 16384 and -16384, little-endian.
+            assert_eq!(
+                packet[SCREAM_HEADER_SIZE..SCREAM_HEADER_SIZE + 4],
+                [0x00, 0x40, 0x00, 0xc0]
+            );
+        }
+    }
+
+    #[test]
+    fn leaves_out_partial_frames() {
+        // 15 byte frames: 76 fit in a packet.
+        let format = ScreamFormat {
+            sample_rate: 44100,
+            sample_bits: 24,
+            channels: 5,
+            channel_mask: 0x37,
+        };
+        let mut samples = vec![0.25; 100 * 5];
+        // Part of another frame, which is never sent.
+        samples.extend_from_slice(&[0.25; 3]);
+        let packets = send_to_local(format, &samples);
+
+        let sizes: Vec<usize> = packets.iter().map(Vec::len).collect();
+        assert_eq!(
+            sizes,
+            [SCREAM_HEADER_SIZE + 76 * 15, SCREAM_HEADER_SIZE + 24 * 15]
+        );
+        assert_eq!(packets[0][0], 0b10000001);
+    }
+
+    #[test]
+    fn rejects_formats_the_header_cannot_carry() {
+        let config = SenderConfig {
+            unicast_target: Some(Ipv4Addr::LOCALHOST),
+            ..SenderConfig::default()
+        };
+        let format = ScreamFormat {
+            sample_rate: 32000,
+            sample_bits: 16,
+            channels: 2,
+            channel_mask: 0x3,
+        };
+
+        assert!(ScreamSender::new(&config, format).is_err());
+        let format = ScreamFormat {
+            sample_rate: 48000,
+            sample_bits: 8,
+            ..format
+        };
+        assert!(ScreamSender::new(&config, format).is_err());
+    }
+
+    fn default_config(channels: u16, sample_rate: u32) -> cpal::SupportedStreamConfig {
+        cpal::SupportedStreamConfig::new(
+            channels,
+            SampleRate(sample_rate),
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        )
+    }
+
+    fn range(channels: u16, min: u32, max: u32) -> cpal::SupportedStreamConfigRange {
+        cpal::SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(min),
+            SampleRate(max),
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        )
+    }
+
+    #[test]
+    fn uses_device_default_if_it_can_be_sent() {
+        let supported = [range(2, 8000, 192000)];
+        let config = SenderConfig::default();
+
+        let chosen = choose_input_config(default_config(2, 44100), &supported, &config).unwrap();
+        assert_eq!(chosen.sample_rate().0, 44100);
+        assert_eq!(chosen.channels(), 2);
+    }
+
+    #[test]
+    fn falls_back_to_a_rate_scream_can_carry() {
+        let supported = [range(1, 8000, 48000), range(2, 8000, 44100)];
+        let config = SenderConfig::default();
+
+        let chosen = choose_input_config(default_config(2, 32000), &supported, &config).unwrap();
+        assert_eq!(chosen.sample_rate().0, 44100);
+        assert_eq!(chosen.channels(), 2);
+
+        let config = SenderConfig {
+            channels: Some(1),
+            ..SenderConfig::default()
+        };
+        let chosen = choose_input_config(default_config(2, 32000), &supported, &config).unwrap();
+        assert_eq!(chosen.sample_rate().0, 48000);
+        assert_eq!(chosen.channels(), 1);
+    }
+
+    #[test]
+    fn rejects_unsupported_input_configs() {
+        let supported = [range(2, 8000, 48000)];
+
+        let config = SenderConfig {
+            sample_rate: Some(96000),
+            ..SenderConfig::default()
+        };
+        assert!(choose_input_config(default_config(2, 48000), &supported, &config).is_err());
+
+        let config = SenderConfig {
+            channels: Some(6),
+            ..SenderConfig::default()
+        };
+        assert!(choose_input_config(default_config(2, 48000), &supported, &config).is_err());
+
+        let config = SenderConfig {
+            sample_rate: Some(32000),
+            ..SenderConfig::default()
+        };
+        assert!(choose_input_config(default_config(2, 32000), &supported, &config).is_err());
+    }
+}
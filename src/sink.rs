@@ -0,0 +1,253 @@
+use crate::config::ReceiverConfig;
+use crate::error::{Error, Result};
+use crate::output_stream::{create_audio_player, select_cpal_device, select_host, AudioPlayer};
+use crate::scream::ScreamFormat;
+use crate::source_reader::SourceBuffer;
+use cpal::traits::DeviceTrait;
+use std::net::IpAddr;
+use std::thread;
+
+/// Destination for decoded audio.
+pub trait AudioSink {
+    /// Starts a new source in the given format, sent from `addr`. Decoded
+    /// samples pushed into the returned buffer are played until it is
+    /// dropped.
+    fn start_source(
+        &mut self,
+        addr: IpAddr,
+        format: &ScreamFormat,
+        gain: f32,
+    ) -> Result<SourceBuffer>;
+
+    /// Called after all sources have been dropped because no packets arrived
+    /// for a while. Sinks can release their resources here.
+    fn stop(&mut self);
+
+    /// Reports errors the sink ran into in the background since the last
+    /// call, such as a failed output stream.
+    fn poll_error(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after the sink reported a failure. All sources have been
+    /// dropped; the sink should get back into a state where it can start
+    /// new ones, waiting if necessary.
+    fn recover(&mut self) -> Result<()> {
+        self.stop();
+        Ok(())
+    }
+}
+
+/// Plays audio on one or more cpal output devices. Every device has its own
+/// buffers and drift correction, so devices with different clocks can play
+/// the same stream.
+pub struct CpalSink {
+    config: ReceiverConfig,
+    outputs: Vec<CpalOutput>,
+}
+
+impl CpalSink {
+    /// Opens the devices named in the config, or the default output device,
+    /// on the configured host.
+    pub fn new(config: &ReceiverConfig) -> Result<CpalSink> {
+        let names: Vec<Option<String>> = match config.output_devices.is_empty() {
+            true => vec![None],
+            false => config.output_devices.iter().cloned().map(Some).collect(),
+        };
+
+        let mut outputs = Vec::new();
+        for name in names {
+            let (device, is_fallback) = find_device(config, name.as_deref())?;
+            outputs.push(CpalOutput {
+                name,
+                device: Some(device),
+                is_fallback,
+                audio_player: None,
+            });
+        }
+
+        Ok(CpalSink {
+            config: config.clone(),
+            outputs,
+        })
+    }
+
+    pub fn with_device(device: cpal::Device, config: &ReceiverConfig) -> CpalSink {
+        let output = CpalOutput {
+            name: device.name().ok(),
+            device: Some(device),
+            is_fallback: false,
+            audio_player: None,
+        };
+
+        CpalSink {
+            config: config.clone(),
+            outputs: vec![output],
+        }
+    }
+}
+
+/// Looks up a device by name, falling back to the default device if
+/// allowed.
+fn find_device(config: &ReceiverConfig, name: Option<&str>) -> Result<(cpal::Device, bool)> {
+    let host = select_host(config.host.as_deref())?;
+    match select_cpal_device(&host, name) {
+        Ok(device) => Ok((device, false)),
+        Err(_) if config.fallback_to_default_device && name.is_some() => {
+            Ok((select_cpal_device(&host, None)?, true))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// One of the devices of a `CpalSink`.
+struct CpalOutput {
+    /// Configured name, `None` for the default device.
+    name: Option<String>,
+    /// `None` while the device is missing.
+    device: Option<cpal::Device>,
+    /// Whether `device` is the default device standing in for a configured
+    /// device that is missing.
+    is_fallback: bool,
+    audio_player: Option<AudioPlayer>,
+}
+
+impl CpalOutput {
+    fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or("default")
+    }
+
+    /// Looks the device up again if it is missing, or switches back to it
+    /// when only the fallback was available.
+    fn refresh_device(&mut self, config: &ReceiverConfig) -> Result<()> {
+        if self.device.is_some() && !self.is_fallback {
+            return Ok(());
+        }
+
+        let (device, is_fallback) = find_device(config, self.name.as_deref())?;
+        if self.is_fallback && !is_fallback {
+            eprintln!("Audio device {} is back, switching to it", self.label());
+        } else if self.device.is_none() && is_fallback {
+            eprintln!(
+                "Audio device {} not available, using the default device",
+                self.label()
+            );
+        }
+
+        self.device = Some(device);
+        self.is_fallback = is_fallback;
+        Ok(())
+    }
+
+    fn start_source(
+        &mut self,
+        format: &ScreamFormat,
+        gain: f32,
+        config: &ReceiverConfig,
+    ) -> Result<SourceBuffer> {
+        // When playing a single source, the output is reopened to match the
+        // new format. Mixed sources are converted to the format of the
+        // output as it is.
+        let reuse_player = match &self.audio_player {
+            Some(player) => config.mix_sources || player.format() == format,
+            None => false,
+        };
+
+        if !reuse_player {
+            // Close the old stream before opening the device again.
+            if let Some(player) = self.audio_player.take() {
+                player.close();
+            }
+            // A failed lookup leaves the device as it was; opening it
+            // reports the actual problem.
+            let _ = self.refresh_device(config);
+
+            let device = self
+                .device
+                .as_ref()
+                .ok_or_else(|| Error::DeviceNotFound(self.label().to_string()))?;
+            self.audio_player = Some(create_audio_player(device, format, config)?);
+        }
+
+        let player = self.audio_player.as_ref().unwrap();
+        Ok(player.add_source(format, gain, config))
+    }
+}
+
+impl AudioSink for CpalSink {
+    fn start_source(
+        &mut self,
+        _addr: IpAddr,
+        format: &ScreamFormat,
+        gain: f32,
+    ) -> Result<SourceBuffer> {
+        let several_outputs = self.outputs.len() > 1;
+        let mut buffers = Vec::new();
+        let mut error = None;
+
+        for output in self.outputs.iter_mut() {
+            match output.start_source(format, gain, &self.config) {
+                Ok(buffer) => buffers.push(buffer),
+                // One missing device shouldn't keep the others from playing.
+                // It is looked up again when the next source starts.
+                Err(e) if several_outputs && e.is_output_failure() => {
+                    eprintln!("Not playing on {}: {}", output.label(), e);
+                    output.device = None;
+                    error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        match error {
+            Some(e) if buffers.is_empty() => Err(e),
+            _ => Ok(SourceBuffer::merge(buffers)),
+        }
+    }
+
+    fn stop(&mut self) {
+        for output in self.outputs.iter_mut() {
+            if let Some(player) = output.audio_player.take() {
+                player.close();
+            }
+        }
+    }
+
+    fn poll_error(&mut self) -> Result<()> {
+        for output in &self.outputs {
+            if let Some(player) = &output.audio_player {
+                player.poll_error()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn recover(&mut self) -> Result<()> {
+        // Device handles may be stale after a device went away, so all of
+        // them are looked up again.
+        for output in self.outputs.iter_mut() {
+            output.audio_player = None;
+            output.device = None;
+            output.is_fallback = false;
+        }
+
+        // Carry on as soon as any device is back; the others are picked up
+        // when a source starts.
+        loop {
+            let mut error = None;
+            for output in self.outputs.iter_mut() {
+                if let Err(e) = output.refresh_device(&self.config) {
+                    error = Some(e);
+                }
+            }
+
+            match error {
+                Some(e) if self.outputs.iter().all(|o| o.device.is_none()) => {
+                    eprintln!("Waiting for audio device: {}", e);
+                    thread::sleep(self.config.device_retry_interval);
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+}
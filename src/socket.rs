@@ -0,0 +1,241 @@
+use crate::config::{ReceiverConfig, SenderConfig};
+use crate::error::{Error, Result};
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+pub const ADDR_ANY: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
+pub const SCREAM_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 77, 77);
+pub const SCREAM_MULTICAST_PORT: u16 = 4010;
+
+/// How long a receive waits for a packet. Idle senders are noticed at this
+/// granularity.
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub fn open_socket(config: &ReceiverConfig) -> Result<UdpSocket> {
+    let interface = match &config.interface {
+        Some(interface) => resolve_interface(interface)?,
+        None => ADDR_ANY,
+    };
+
+    let socket = match config.unicast {
+        true => UdpSocket::bind(SocketAddrV4::new(interface, config.port))?,
+        false => open_multicast_socket(config, interface)?,
+    };
+    socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+
+    Ok(socket)
+}
+
+/// Opens a socket to send from, on the configured interface. On Linux,
+/// multicast packets leave through the interface the socket is bound to.
+pub fn open_sender_socket(config: &SenderConfig) -> Result<UdpSocket> {
+    let interface = match &config.interface {
+        Some(interface) => resolve_interface(interface)?,
+        None => ADDR_ANY,
+    };
+
+    let socket = UdpSocket::bind(SocketAddrV4::new(interface, 0))?;
+    if config.unicast_target.is_none() {
+        socket.set_multicast_ttl_v4(config.multicast_ttl)?;
+    }
+
+    Ok(socket)
+}
+
+/// Receives a datagram along with the time it arrived, or `None` if the
+/// socket's read timeout expired first.
+pub fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Option<Received>> {
+    match recv_with_timestamp(socket, buf) {
+        Ok(received) => Ok(Some(received)),
+        // Unix reports an expired read timeout as WouldBlock. A signal such
+        // as Ctrl-C interrupts the wait the same way.
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Size, sender and arrival time of a datagram.
+pub type Received = (usize, SocketAddr, Instant);
+
+/// Has the kernel timestamp datagrams as they arrive, so that the time spent
+/// queued in the socket doesn't show up as network jitter.
+#[cfg(target_os = "linux")]
+pub fn enable_timestamps(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPNS,
+            &enable as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_timestamps(_socket: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+/// Uses the kernel's timestamp if the socket has them enabled, and the
+/// current time otherwise.
+#[cfg(target_os = "linux")]
+fn recv_with_timestamp(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Received> {
+    use std::mem;
+    use std::net::{Ipv6Addr, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // Aligned for the control message headers.
+    let mut control = [0u64; 8];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of_val(&addr) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let size = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let now = Instant::now();
+    let mut received_at = now;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_TIMESTAMPNS {
+            let timestamp =
+                unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec) };
+            let arrival =
+                UNIX_EPOCH + Duration::new(timestamp.tv_sec as u64, timestamp.tv_nsec as u32);
+            // The timestamp is wall clock time, so it's converted by its age.
+            if let Ok(age) = SystemTime::now().duration_since(arrival) {
+                received_at = now.checked_sub(age).unwrap_or(now);
+            }
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    let sender = match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(&addr as *const _ as *const libc::sockaddr_in) };
+            SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            ))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(&addr as *const _ as *const libc::sockaddr_in6) };
+            SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            ))
+        }
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "unknown address family",
+            ))
+        }
+    };
+
+    Ok((size as usize, sender, received_at))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv_with_timestamp(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Received> {
+    let (size, addr) = socket.recv_from(buf)?;
+    Ok((size, addr, Instant::now()))
+}
+
+fn open_multicast_socket(config: &ReceiverConfig, interface: Ipv4Addr) -> Result<UdpSocket> {
+    // On Unix, binding to the group address keeps packets sent to other
+    // groups on the same port out of this socket. Windows does not allow
+    // binding to a multicast address.
+    let bind_addr = if cfg!(unix) {
+        config.multicast_group
+    } else {
+        ADDR_ANY
+    };
+
+    let socket = UdpSocket::bind(SocketAddrV4::new(bind_addr, config.port))?;
+    socket.join_multicast_v4(&config.multicast_group, &interface)?;
+
+    Ok(socket)
+}
+
+/// Accepts either an IPv4 address or the name of a network interface, in
+/// which case the interface's first IPv4 address is used.
+fn resolve_interface(interface: &str) -> Result<Ipv4Addr> {
+    if let Ok(addr) = interface.parse::<Ipv4Addr>() {
+        return Ok(addr);
+    }
+
+    interface_ipv4_addr(interface).ok_or_else(|| Error::InterfaceNotFound(interface.to_string()))
+}
+
+#[cfg(unix)]
+fn interface_ipv4_addr(name: &str) -> Option<Ipv4Addr> {
+    use std::ffi::CStr;
+
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return None;
+    }
+
+    let mut result = None;
+    let mut current = ifaddrs;
+
+    while !current.is_null() {
+        let ifaddr = unsafe { &*current };
+        current = ifaddr.ifa_next;
+
+        if ifaddr.ifa_addr.is_null() || ifaddr.ifa_name.is_null() {
+            continue;
+        }
+
+        let ifaddr_name = unsafe { CStr::from_ptr(ifaddr.ifa_name) };
+        let family = unsafe { (*ifaddr.ifa_addr).sa_family } as libc::c_int;
+
+        if family == libc::AF_INET && ifaddr_name.to_bytes() == name.as_bytes() {
+            let sockaddr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+            result = Some(Ipv4Addr::from(u32::from_be(sockaddr.sin_addr.s_addr)));
+            break;
+        }
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    result
+}
+
+#[cfg(not(unix))]
+fn interface_ipv4_addr(_name: &str) -> Option<Ipv4Addr> {
+    None
+}
@@ -0,0 +1,143 @@
+use crate::config::ReceiverConfig;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourcePolicy {
+    /// Keep playing the first active sender until it goes quiet.
+    First,
+    /// Switch to a sender as soon as it starts transmitting.
+    Latest,
+    /// Only play the sender given in `ReceiverConfig::source`.
+    Pick,
+}
+
+impl FromStr for SourcePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(SourcePolicy::First),
+            "latest" => Ok(SourcePolicy::Latest),
+            "pick" => Ok(SourcePolicy::Pick),
+            _ => Err(format!("unknown source policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for SourcePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SourcePolicy::First => "first",
+            SourcePolicy::Latest => "latest",
+            SourcePolicy::Pick => "pick",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Gain applied to a single sender, given as `ADDRESS=GAIN`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceGain {
+    pub addr: IpAddr,
+    pub gain: f32,
+}
+
+impl FromStr for SourceGain {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, gain) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected ADDRESS=GAIN, got '{}'", s))?;
+
+        Ok(SourceGain {
+            addr: addr
+                .trim()
+                .parse()
+                .map_err(|e| format!("invalid address: {}", e))?,
+            gain: gain
+                .trim()
+                .parse()
+                .map_err(|e| format!("invalid gain: {}", e))?,
+        })
+    }
+}
+
+/// Decides which sender's packets get played when several are transmitting
+/// to the same group.
+pub struct SourceSelector {
+    allowed: Vec<IpAddr>,
+    denied: Vec<IpAddr>,
+    policy: SourcePolicy,
+    picked: Option<IpAddr>,
+    gains: Vec<SourceGain>,
+    current: Option<IpAddr>,
+    last_seen: HashMap<IpAddr, Instant>,
+    /// A sender that has not been heard from for this long no longer counts
+    /// as active.
+    timeout: Duration,
+}
+
+impl SourceSelector {
+    pub fn new(config: &ReceiverConfig) -> SourceSelector {
+        SourceSelector {
+            allowed: config.allow_source.clone(),
+            denied: config.deny_source.clone(),
+            policy: config.source_policy,
+            picked: config.source,
+            gains: config.source_gain.clone(),
+            current: None,
+            last_seen: HashMap::new(),
+            timeout: config.idle_timeout,
+        }
+    }
+
+    pub fn is_permitted(&self, addr: IpAddr) -> bool {
+        !self.denied.contains(&addr) && (self.allowed.is_empty() || self.allowed.contains(&addr))
+    }
+
+    pub fn gain(&self, addr: IpAddr) -> f32 {
+        self.gains
+            .iter()
+            .find(|g| g.addr == addr)
+            .map_or(1.0, |g| g.gain)
+    }
+
+    /// Records a packet from `addr` and returns whether it should be played.
+    pub fn accept(&mut self, addr: IpAddr, now: Instant) -> bool {
+        if !self.is_permitted(addr) {
+            return false;
+        }
+
+        let was_active = self.is_active(addr, now);
+        self.last_seen.insert(addr, now);
+        let timeout = self.timeout;
+        self.last_seen
+            .retain(|_, seen| now.duration_since(*seen) < timeout);
+
+        let current_active = self.current.is_some_and(|c| self.is_active(c, now));
+
+        let switch = match self.policy {
+            SourcePolicy::First => !current_active,
+            SourcePolicy::Latest => !current_active || !was_active,
+            SourcePolicy::Pick => self.picked == Some(addr),
+        };
+
+        if switch && self.current != Some(addr) {
+            eprintln!("Playing source {}", addr);
+            self.current = Some(addr);
+        }
+
+        self.current == Some(addr)
+    }
+
+    fn is_active(&self, addr: IpAddr, now: Instant) -> bool {
+        self.last_seen
+            .get(&addr)
+            .is_some_and(|seen| now.duration_since(*seen) < self.timeout)
+    }
+}
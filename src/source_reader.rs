@@ -0,0 +1,485 @@
+use crate::channel_map::ChannelMap;
+use crate::concealment::Concealer;
+use crate::config::ReceiverConfig;
+use crate::drift::DriftController;
+use crate::output_stream::BufferSample;
+use crate::resampler::Resampler;
+use crate::scream::{ScreamFormat, ScreamHeader};
+use ringbuf::RingBuffer;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the measured latency is printed, if enabled.
+const LATENCY_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum OutputMode {
+    Stopped,
+    ChuggingAlong,
+}
+
+/// State shared by both ends of a ring buffer.
+#[derive(Default)]
+struct QueueState {
+    closed: AtomicBool,
+    /// Fill level the output should keep, in frames. 0 leaves it to the
+    /// config.
+    target_fill: AtomicUsize,
+}
+
+/// Receiving end of a source's pipeline: decoded samples are pushed here.
+/// Dropping it lets the output remove the source once it has played out.
+pub struct SourceBuffer {
+    /// One ring buffer per output the source is played on.
+    buffers: Vec<(ringbuf::Producer<BufferSample>, Arc<QueueState>)>,
+}
+
+impl SourceBuffer {
+    /// Combines buffers so that every sample goes to all of them, e.g. to
+    /// play a source on several devices.
+    pub fn merge(buffers: impl IntoIterator<Item = SourceBuffer>) -> SourceBuffer {
+        let buffers = buffers
+            .into_iter()
+            .flat_map(|mut buffer| std::mem::take(&mut buffer.buffers))
+            .collect();
+        SourceBuffer { buffers }
+    }
+
+    /// Queues a decoded sample, handing it back if a buffer is full. The
+    /// other buffers still get the sample.
+    pub fn push(&mut self, sample: BufferSample) -> Result<(), BufferSample> {
+        let mut result = Ok(());
+        for (buffer, _) in self.buffers.iter_mut() {
+            if buffer.push(sample).is_err() {
+                result = Err(sample);
+            }
+        }
+        result
+    }
+
+    /// Changes how many frames the outputs keep buffered, e.g. to adapt to
+    /// network jitter.
+    pub fn set_target_fill(&self, frames: usize) {
+        for (_, state) in &self.buffers {
+            state.target_fill.store(frames, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for SourceBuffer {
+    fn drop(&mut self) {
+        for (_, state) in &self.buffers {
+            state.closed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Reading end of a `SourceBuffer`, for sinks that take the decoded samples
+/// as they are instead of playing them through an output stream.
+pub struct SampleQueue {
+    cons: ringbuf::Consumer<BufferSample>,
+    state: Arc<QueueState>,
+}
+
+impl SampleQueue {
+    /// Creates a queue holding up to `capacity` samples and the buffer that
+    /// feeds it.
+    pub fn new(capacity: usize) -> (SampleQueue, SourceBuffer) {
+        let (prod, cons) = RingBuffer::<BufferSample>::new(capacity).split();
+        let state = Arc::new(QueueState::default());
+
+        let queue = SampleQueue {
+            cons,
+            state: state.clone(),
+        };
+        let buffer = SourceBuffer {
+            buffers: vec![(prod, state)],
+        };
+
+        (queue, buffer)
+    }
+
+    pub fn pop(&mut self) -> Option<BufferSample> {
+        self.cons.pop()
+    }
+
+    /// Whether the buffer has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.state.closed.load(Ordering::Relaxed)
+    }
+
+    /// Fill level set with `SourceBuffer::set_target_fill`, if any.
+    pub fn target_fill(&self) -> Option<usize> {
+        match self.state.target_fill.load(Ordering::Relaxed) {
+            0 => None,
+            frames => Some(frames),
+        }
+    }
+
+    /// Whether the buffer has been dropped and every sample taken out.
+    pub fn is_finished(&self) -> bool {
+        self.is_closed() && self.cons.is_empty()
+    }
+}
+
+/// Output side of a source's pipeline. Pulls samples from the ring buffer,
+/// conceals gaps, resamples them to the output rate with its own drift
+/// correction, and maps them to the output's channels.
+pub struct SourceReader {
+    queue: SampleQueue,
+    resampler: Resampler,
+    channel_map: ChannelMap,
+    drift_controller: DriftController,
+    concealer: Concealer,
+    output_mode: OutputMode,
+    sample_rate: u32,
+    /// Target fill of the ring buffer, in frames at the stream's rate.
+    samples_buffered: usize,
+    necessary_buffer_size: usize,
+    /// Fill level the underrun threshold is relative to. Follows a falling
+    /// target right away, but a rising one only once the buffer has filled
+    /// up to it, so that a larger target isn't mistaken for an underrun.
+    reached_buffer_size: usize,
+    gain: f32,
+    /// Length of the fade out at the end of the source, in output frames.
+    fade_frames: usize,
+    latency: LatencyMeter,
+    config: ReceiverConfig,
+}
+
+impl SourceReader {
+    pub fn new(
+        format: &ScreamFormat,
+        output_channels: u16,
+        output_sample_rate: u32,
+        gain: f32,
+        config: &ReceiverConfig,
+    ) -> (SourceReader, SourceBuffer) {
+        let samples_buffered = config.samples_buffered_for(format.sample_rate());
+        let (queue, buffer) = SampleQueue::new(config.queue_capacity_for(format.sample_rate()));
+
+        if output_sample_rate != format.sample_rate() {
+            eprintln!(
+                "Resampling from {} Hz to {} Hz",
+                format.sample_rate(),
+                output_sample_rate
+            );
+        }
+
+        let resampler = Resampler::new(
+            format.sample_rate(),
+            output_sample_rate,
+            format.channels() as usize,
+            config.resample_quality,
+        );
+
+        let channel_map = match &config.channel_map {
+            Some(channel_map) => channel_map.clone(),
+            None => {
+                ChannelMap::for_device(&format.speaker_positions(), output_channels, config.upmix)
+            }
+        };
+
+        let reader = SourceReader {
+            queue,
+            resampler,
+            channel_map,
+            drift_controller: DriftController::new(config.max_drift_correction_ppm),
+            concealer: Concealer::new(config.concealment, format.sample_rate(), config.fade),
+            output_mode: OutputMode::Stopped,
+            sample_rate: format.sample_rate(),
+            samples_buffered,
+            necessary_buffer_size: samples_buffered,
+            reached_buffer_size: samples_buffered,
+            gain,
+            fade_frames: (config.fade.as_secs_f64() * output_sample_rate as f64) as usize,
+            latency: LatencyMeter::default(),
+            config: config.clone(),
+        };
+
+        (reader, buffer)
+    }
+
+    /// Whether the source is gone and everything it sent has been played.
+    pub fn is_finished(&self) -> bool {
+        self.queue.is_finished()
+    }
+
+    /// Called once per output callback before `next_frame`, with the number
+    /// of frames the output is about to request and how long it takes until
+    /// the first of them is heard.
+    pub fn prepare(&mut self, frames: usize, output_sample_rate: u32, output_delay: Duration) {
+        // The ring buffer holds frames at the stream's rate, so the amount
+        // requested by the output is converted to input frames.
+        let samples_requested = (frames as f64 * self.resampler.ratio()).ceil() as usize;
+        let target_fill = self.queue.target_fill().unwrap_or(self.samples_buffered);
+        self.necessary_buffer_size = std::cmp::max(target_fill, samples_requested);
+
+        // Way too much buffered, e.g. after the output stalled: skip ahead
+        // instead of slowly catching up.
+        let skip_threshold =
+            (self.necessary_buffer_size as f32 * self.config.faster_playback_threshold) as usize;
+        if self.queue.cons.len() > skip_threshold {
+            let skip_to = (self.necessary_buffer_size as f32
+                * self.config.normal_playback_threshold) as usize;
+            let skipped = self
+                .queue
+                .cons
+                .discard(self.queue.cons.len().saturating_sub(skip_to));
+            eprintln!("Buffer overrun, skipped {} samples", skipped);
+            self.drift_controller.reset_fill();
+        }
+
+        // While playing, the drift controller fills the buffer up to a
+        // raised target gradually.
+        if self.output_mode == OutputMode::Stopped
+            || self.necessary_buffer_size <= self.reached_buffer_size
+            || self.queue.cons.len() >= self.necessary_buffer_size
+        {
+            self.reached_buffer_size = self.necessary_buffer_size;
+        }
+
+        // The thresholds are relative to the fill level before the output
+        // takes its share, so they are only checked here and not per frame.
+        // Once the sender is gone, whatever is left is played out.
+        let new_output_mode = if self.queue.is_closed() && !self.queue.cons.is_empty() {
+            OutputMode::ChuggingAlong
+        } else {
+            get_output_mode(
+                self.output_mode,
+                self.reached_buffer_size,
+                self.queue.cons.len(),
+                &self.config,
+            )
+        };
+        self.set_output_mode(new_output_mode);
+
+        if self.output_mode == OutputMode::ChuggingAlong {
+            let elapsed_seconds = frames as f64 / output_sample_rate as f64;
+            let correction = self.drift_controller.update(
+                self.queue.cons.len(),
+                self.necessary_buffer_size,
+                elapsed_seconds,
+            );
+            self.resampler.set_drift_correction(correction);
+        }
+
+        if self.config.report_latency {
+            self.measure_latency(frames, output_sample_rate, output_delay);
+        }
+    }
+
+    /// Latency of the samples just received: what is buffered ahead of them
+    /// plus the output's own delay.
+    fn measure_latency(&mut self, frames: usize, output_sample_rate: u32, output_delay: Duration) {
+        if self.output_mode == OutputMode::ChuggingAlong {
+            let buffered = self.queue.cons.len() as f64 / self.sample_rate as f64;
+            self.latency.add(buffered, output_delay.as_secs_f64());
+        }
+
+        self.latency.elapsed += frames as f64 / output_sample_rate as f64;
+        if self.latency.elapsed >= LATENCY_REPORT_INTERVAL.as_secs_f64() {
+            if self.latency.count > 0 {
+                eprintln!(
+                    "Latency: {:.1} ms ({:.1} to {:.1} ms), of which output {:.1} ms, target {:.1} ms",
+                    self.latency.total / self.latency.count as f64 * 1000.0,
+                    self.latency.min * 1000.0,
+                    self.latency.max * 1000.0,
+                    self.latency.output_delay * 1000.0,
+                    self.necessary_buffer_size as f64 / self.sample_rate as f64 * 1000.0
+                        + self.latency.output_delay * 1000.0
+                );
+            }
+            self.latency = LatencyMeter::default();
+        }
+    }
+
+    /// Produces the next output frame, in the output's channel layout.
+    pub fn next_frame(&mut self) -> BufferSample {
+        let mut ran_dry = false;
+
+        let sample = self.resampler.next_frame(|| {
+            match get_sample(self.output_mode, &mut self.queue.cons) {
+                Some(sample) => self.concealer.feed(&sample),
+                None => {
+                    if self.output_mode == OutputMode::ChuggingAlong {
+                        ran_dry = true;
+                        self.output_mode = OutputMode::Stopped;
+                    }
+                    self.concealer.conceal()
+                }
+            }
+        });
+
+        if ran_dry {
+            self.drift_controller.reset_fill();
+            eprintln!("Output mode changed: Stopped, buffer ran dry");
+        }
+
+        let gain = self.gain * self.end_fade_gain();
+        let mut output_sample = self.channel_map.apply(&sample);
+        for channel_sample in output_sample.iter_mut() {
+            *channel_sample *= gain;
+        }
+
+        output_sample
+    }
+
+    /// Fades out the last samples of a source whose sender is gone. Starting
+    /// and gaps are taken care of by the concealer.
+    fn end_fade_gain(&self) -> f32 {
+        if self.fade_frames == 0 || !self.queue.is_closed() {
+            return 1.0;
+        }
+
+        let frames_left = (self.queue.cons.len() as f64 / self.resampler.ratio()) as usize;
+        frames_left.min(self.fade_frames) as f32 / self.fade_frames as f32
+    }
+
+    fn set_output_mode(&mut self, output_mode: OutputMode) {
+        if self.output_mode != output_mode {
+            self.drift_controller.reset_fill();
+            eprintln!(
+                "Output mode changed: {:?}, samples: {}, buffer_size: {}",
+                output_mode,
+                self.queue.cons.len(),
+                self.necessary_buffer_size
+            );
+        }
+
+        self.output_mode = output_mode;
+    }
+}
+
+/// Latency measurements collected between two reports, in seconds.
+#[derive(Default)]
+struct LatencyMeter {
+    total: f64,
+    min: f64,
+    max: f64,
+    count: usize,
+    /// Most recent delay of the output device.
+    output_delay: f64,
+    elapsed: f64,
+}
+
+impl LatencyMeter {
+    fn add(&mut self, buffered: f64, output_delay: f64) {
+        let latency = buffered + output_delay;
+        if self.count == 0 || latency < self.min {
+            self.min = latency;
+        }
+        self.max = self.max.max(latency);
+        self.total += latency;
+        self.count += 1;
+        self.output_delay = output_delay;
+    }
+}
+
+fn get_output_mode(
+    current_output_mode: OutputMode,
+    samples_requested: usize,
+    samples_available: usize,
+    config: &ReceiverConfig,
+) -> OutputMode {
+    if samples_available == 0 {
+        return OutputMode::Stopped;
+    }
+
+    // After running dry, wait until the buffer is back in the normal range
+    // before playing again.
+    if current_output_mode == OutputMode::Stopped {
+        let back_to_chug = (samples_requested as f32 / config.normal_playback_threshold) as usize;
+        if samples_available >= back_to_chug {
+            return OutputMode::ChuggingAlong;
+        }
+        return OutputMode::Stopped;
+    }
+
+    // The drift controller should never let the buffer get this low; if it
+    // does anyway, rebuffer instead of playing on with no margin.
+    if samples_available < (samples_requested as f32 * config.slower_playback_threshold) as usize {
+        return OutputMode::Stopped;
+    }
+
+    current_output_mode
+}
+
+fn get_sample(
+    output_mode: OutputMode,
+    cons: &mut ringbuf::Consumer<BufferSample>,
+) -> Option<BufferSample> {
+    match output_mode {
+        OutputMode::Stopped => None,
+        OutputMode::ChuggingAlong => cons.pop(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAMES: usize = 256;
+
+    fn reader() -> (SourceReader, SourceBuffer) {
+        let format = ScreamFormat {
+            sample_rate: 48000,
+            sample_bits: 16,
+            channels: 2,
+            channel_mask: 0x3,
+        };
+        SourceReader::new(&format, 2, 48000, 1.0, &ReceiverConfig::default())
+    }
+
+    fn fill(buffer: &mut SourceBuffer, frames: usize) {
+        for _ in 0..frames {
+            buffer.push([0.5; crate::scream::MAX_CHANNELS]).unwrap();
+        }
+    }
+
+    fn play(reader: &mut SourceReader) {
+        reader.prepare(FRAMES, 48000, Duration::ZERO);
+        for _ in 0..FRAMES {
+            reader.next_frame();
+        }
+    }
+
+    #[test]
+    fn rising_target_is_not_an_underrun() {
+        let (mut reader, mut buffer) = reader();
+        fill(&mut buffer, 2048 + FRAMES);
+        play(&mut reader);
+        assert_eq!(reader.output_mode, OutputMode::ChuggingAlong);
+
+        // Four times the target is far more than is buffered, but playback
+        // goes on and the buffer fills up over time.
+        buffer.set_target_fill(4 * 2048);
+        fill(&mut buffer, FRAMES);
+        play(&mut reader);
+        assert_eq!(reader.output_mode, OutputMode::ChuggingAlong);
+    }
+
+    #[test]
+    fn rebuffers_below_previous_target() {
+        let (mut reader, mut buffer) = reader();
+        fill(&mut buffer, 2048 + FRAMES);
+        play(&mut reader);
+
+        buffer.set_target_fill(4 * 2048);
+        for _ in 0..4 {
+            play(&mut reader);
+        }
+        assert_eq!(reader.output_mode, OutputMode::ChuggingAlong);
+
+        // Less than half of the old target left.
+        play(&mut reader);
+        play(&mut reader);
+        assert_eq!(reader.output_mode, OutputMode::Stopped);
+
+        // Once stopped, the new target has to be reached.
+        fill(&mut buffer, 2048 + FRAMES);
+        play(&mut reader);
+        assert_eq!(reader.output_mode, OutputMode::Stopped);
+    }
+}
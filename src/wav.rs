@@ -0,0 +1,260 @@
+use crate::output_stream::BufferSample;
+use crate::pcm::to_int_sample;
+use crate::scream::{ScreamFormat, ScreamHeader};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// KSDATAFORMAT_SUBTYPE_PCM and KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, without the
+/// leading format tag.
+const SUBTYPE_GUID_TAIL: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+];
+
+/// Sample layout described by a WAV header.
+pub struct WavSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits: u16,
+    /// IEEE float samples instead of signed integers.
+    pub float: bool,
+    pub channel_mask: u16,
+}
+
+impl WavSpec {
+    fn block_align(&self) -> u16 {
+        self.channels * self.bits / 8
+    }
+}
+
+/// Writes the header of a WAV file holding `data_size` bytes of samples and
+/// returns the offset of the data size field. A stream of unknown length
+/// uses `u32::MAX`.
+pub fn write_header(out: &mut impl Write, spec: &WavSpec, data_size: u32) -> io::Result<u64> {
+    // Like Windows, use WAVE_FORMAT_EXTENSIBLE whenever a plain header can't
+    // describe the speaker layout or sample width unambiguously.
+    let extensible = spec.channels > 2 || (spec.bits > 16 && !spec.float);
+    let fmt_size: u32 = if extensible { 40 } else { 16 };
+    let data_size_offset = 12 + 8 + fmt_size as u64 + 4;
+    let format_tag = if spec.float {
+        WAVE_FORMAT_IEEE_FLOAT
+    } else {
+        WAVE_FORMAT_PCM
+    };
+
+    out.write_all(b"RIFF")?;
+    out.write_u32::<LittleEndian>(data_size.saturating_add(data_size_offset as u32 - 4))?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_u32::<LittleEndian>(fmt_size)?;
+    out.write_u16::<LittleEndian>(if extensible {
+        WAVE_FORMAT_EXTENSIBLE
+    } else {
+        format_tag
+    })?;
+    out.write_u16::<LittleEndian>(spec.channels)?;
+    out.write_u32::<LittleEndian>(spec.sample_rate)?;
+    out.write_u32::<LittleEndian>(spec.sample_rate * spec.block_align() as u32)?;
+    out.write_u16::<LittleEndian>(spec.block_align())?;
+    out.write_u16::<LittleEndian>(spec.bits)?;
+    if extensible {
+        out.write_u16::<LittleEndian>(22)?;
+        out.write_u16::<LittleEndian>(spec.bits)?;
+        out.write_u32::<LittleEndian>(spec.channel_mask as u32)?;
+        out.write_u16::<LittleEndian>(format_tag)?;
+        out.write_all(&SUBTYPE_GUID_TAIL)?;
+    }
+
+    out.write_all(b"data")?;
+    out.write_u32::<LittleEndian>(data_size)?;
+
+    Ok(data_size_offset)
+}
+
+/// Writes integer PCM samples to a WAV file. The sizes in the header are
+/// filled in by `finish`.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    format: ScreamFormat,
+    data_size_offset: u64,
+    data_bytes: u64,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, format: &ScreamFormat) -> io::Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let spec = WavSpec {
+            channels: format.channels,
+            sample_rate: format.sample_rate,
+            bits: format.sample_bits as u16,
+            float: false,
+            channel_mask: format.channel_mask(),
+        };
+        let data_size_offset = write_header(&mut file, &spec, 0)?;
+
+        Ok(WavWriter {
+            file,
+            format: *format,
+            data_size_offset,
+            data_bytes: 0,
+        })
+    }
+
+    pub fn write_frame(&mut self, frame: &BufferSample) -> io::Result<()> {
+        for &sample in &frame[..self.format.channels as usize] {
+            let sample = to_int_sample(sample, self.format.sample_bits);
+            match self.format.sample_bits {
+                16 => self.file.write_i16::<LittleEndian>(sample as i16)?,
+                24 => self.file.write_i24::<LittleEndian>(sample)?,
+                _ => self.file.write_i32::<LittleEndian>(sample)?,
+            }
+        }
+
+        self.data_bytes += self.format.frame_bytes() as u64;
+        Ok(())
+    }
+
+    /// Fills in the chunk sizes and closes the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        // RIFF sizes are 32 bit; a longer recording is still readable by
+        // tools that trust the file size instead.
+        let data_size = self.data_bytes.min(u32::MAX as u64 - self.data_size_offset);
+
+        // Chunks are padded to an even size.
+        let padding = self.data_bytes % 2;
+        if padding == 1 {
+            self.file.write_u8(0)?;
+        }
+        let riff_size = self.data_size_offset - 4 + data_size + padding;
+
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_u32::<LittleEndian>(riff_size as u32)?;
+        self.file.seek(SeekFrom::Start(self.data_size_offset))?;
+        self.file.write_u32::<LittleEndian>(data_size as u32)?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scream::MAX_CHANNELS;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn writes_plain_header() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits: 16,
+            float: false,
+            channel_mask: 0x3,
+        };
+        let mut bytes = Vec::new();
+        assert_eq!(write_header(&mut bytes, &spec, 400).unwrap(), 40);
+
+        assert_eq!(bytes.len(), 44);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), 36 + 400);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&bytes, 16), 16);
+        assert_eq!(u16_at(&bytes, 20), WAVE_FORMAT_PCM);
+        assert_eq!(u16_at(&bytes, 22), 2);
+        assert_eq!(u32_at(&bytes, 24), 44100);
+        assert_eq!(u32_at(&bytes, 28), 44100 * 4);
+        assert_eq!(u16_at(&bytes, 32), 4);
+        assert_eq!(u16_at(&bytes, 34), 16);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 400);
+    }
+
+    #[test]
+    fn writes_extensible_header() {
+        let spec = WavSpec {
+            channels: 6,
+            sample_rate: 48000,
+            bits: 24,
+            float: false,
+            channel_mask: 0x3f,
+        };
+        let mut bytes = Vec::new();
+        assert_eq!(write_header(&mut bytes, &spec, 0).unwrap(), 64);
+
+        assert_eq!(bytes.len(), 68);
+        assert_eq!(u32_at(&bytes, 4), 60);
+        assert_eq!(u32_at(&bytes, 16), 40);
+        assert_eq!(u16_at(&bytes, 20), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(u16_at(&bytes, 22), 6);
+        assert_eq!(u32_at(&bytes, 28), 48000 * 18);
+        assert_eq!(u16_at(&bytes, 32), 18);
+        assert_eq!(u16_at(&bytes, 34), 24);
+        assert_eq!(u16_at(&bytes, 36), 22);
+        assert_eq!(u16_at(&bytes, 38), 24);
+        assert_eq!(u32_at(&bytes, 40), 0x3f);
+        assert_eq!(u16_at(&bytes, 44), WAVE_FORMAT_PCM);
+        assert_eq!(bytes[46..60], SUBTYPE_GUID_TAIL);
+        assert_eq!(&bytes[60..64], b"data");
+        assert_eq!(u32_at(&bytes, 64), 0);
+    }
+
+    #[test]
+    fn writes_float_header_of_unknown_length() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits: 32,
+            float: true,
+            channel_mask: 0x3,
+        };
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, &spec, u32::MAX).unwrap();
+
+        assert_eq!(u32_at(&bytes, 4), u32::MAX);
+        assert_eq!(u16_at(&bytes, 20), WAVE_FORMAT_IEEE_FLOAT);
+        assert_eq!(u32_at(&bytes, 40), u32::MAX);
+    }
+
+    #[test]
+    fn writer_pads_odd_data_and_fills_in_sizes() {
+        let path =
+            std::env::temp_dir().join(format!("screamreader-wav-{}.wav", std::process::id()));
+        let format = ScreamFormat {
+            sample_rate: 48000,
+            sample_bits: 24,
+            channels: 1,
+            channel_mask: 0x4,
+        };
+
+        let mut writer = WavWriter::create(&path, &format).unwrap();
+        for sample in [0.5, -0.5, -1.0] {
+            let mut frame = [0.0; MAX_CHANNELS];
+            frame[0] = sample;
+            writer.write_frame(&frame).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(u16_at(&bytes, 20), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(u32_at(&bytes, 64), 9);
+        assert_eq!(u32_at(&bytes, 4), 60 + 9 + 1);
+        assert_eq!(
+            bytes[68..],
+            [0x00, 0x00, 0x40, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x80, 0x00]
+        );
+    }
+}